@@ -0,0 +1,18 @@
+-- undo adding track_replies column, SQLite can't drop columns so recreate the table
+create table user_info_backup (
+    id integer primary key autoincrement not null,
+    upstream_type text not null,
+    chat_id text not null,
+    user_id text not null,
+    adapter text not null,
+    linked_user_id text not null,
+    last_update datetime not null,
+    verified boolean not null default 1
+);
+
+insert into user_info_backup select id, upstream_type, chat_id, user_id, adapter, linked_user_id, last_update, verified from user_info;
+drop table user_info;
+alter table user_info_backup rename to user_info;
+
+create index user_info_by_upstream on user_info(upstream_type);
+create unique index user_infos_uniq on user_info(upstream_type, chat_id, user_id, adapter, linked_user_id);
@@ -0,0 +1,2 @@
+-- Allow user infos to watch replies to linked user's comments and topics
+alter table user_info add column track_replies boolean not null default 0;
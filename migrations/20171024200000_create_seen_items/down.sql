@@ -0,0 +1,2 @@
+-- undo creating table seen_item
+drop table seen_item;
@@ -0,0 +1,10 @@
+-- Create table for items that were already delivered for each user info
+create table seen_item (
+    id integer primary key autoincrement not null,
+    user_info_id integer not null references user_info(id) on delete cascade,
+    item_id text not null,
+    content_hash text not null,
+    seen_at datetime not null
+);
+
+create unique index seen_items_uniq on seen_item(user_info_id, item_id);
@@ -0,0 +1,14 @@
+-- undo adding event columns, SQLite can't drop columns so recreate the table
+create table seen_item_backup (
+    id integer primary key autoincrement not null,
+    user_info_id integer not null references user_info(id) on delete cascade,
+    item_id text not null,
+    content_hash text not null,
+    seen_at datetime not null
+);
+
+insert into seen_item_backup select id, user_info_id, item_id, content_hash, seen_at from seen_item;
+drop table seen_item;
+alter table seen_item_backup rename to seen_item;
+
+create unique index seen_items_uniq on seen_item(user_info_id, item_id);
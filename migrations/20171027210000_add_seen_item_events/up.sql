@@ -0,0 +1,4 @@
+-- Remember upstream event for each delivered item so it can be edited or redacted later
+alter table seen_item add column event_id text;
+alter table seen_item add column item_kind text not null default 'Post';
+alter table seen_item add column item_date datetime not null default '1970-01-01 00:00:00';
@@ -0,0 +1,2 @@
+-- undo creating table room_permissions
+drop table room_permissions;
@@ -0,0 +1,11 @@
+-- Create table for minimum power levels required for actions in the room
+create table room_permissions (
+    id integer primary key autoincrement not null,
+    upstream_type text not null,
+    chat_id text not null,
+    link_level integer not null default 0,
+    link_others_level integer not null default 50,
+    settings_level integer not null default 50
+);
+
+create unique index room_permissions_uniq on room_permissions(upstream_type, chat_id);
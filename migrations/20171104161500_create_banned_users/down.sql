@@ -0,0 +1,2 @@
+-- undo creating table banned_user
+drop table banned_user;
@@ -0,0 +1,9 @@
+-- Create table for users that are not allowed to use the bot
+create table banned_user (
+    id integer primary key autoincrement not null,
+    upstream_type text not null,
+    user_id text not null,
+    banned_at datetime not null
+);
+
+create unique index banned_users_uniq on banned_user(upstream_type, user_id);
@@ -0,0 +1,2 @@
+-- undo creating table room_settings
+drop table room_settings;
@@ -0,0 +1,10 @@
+-- Create table for per-chat settings, stored as name-value pairs
+create table room_settings (
+    id integer primary key autoincrement not null,
+    upstream_type text not null,
+    chat_id text not null,
+    name text not null,
+    value text not null
+);
+
+create unique index room_settings_uniq on room_settings(upstream_type, chat_id, name);
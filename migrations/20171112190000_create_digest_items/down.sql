@@ -0,0 +1,2 @@
+-- undo creating table digest_item
+drop table digest_item;
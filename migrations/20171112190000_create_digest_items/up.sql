@@ -0,0 +1,14 @@
+-- Create table for updates held back until the next digest of the chat
+create table digest_item (
+    id integer primary key autoincrement not null,
+    user_info_id integer not null references user_info(id),
+    upstream_type text not null,
+    chat_id text not null,
+    linked_user_id text not null,
+    topic_id text not null,
+    title text not null,
+    url text not null,
+    queued_at datetime not null
+);
+
+create index digest_item_chat on digest_item(upstream_type, chat_id);
@@ -0,0 +1,2 @@
+-- undo creating table link_filter
+drop table link_filter;
@@ -0,0 +1,9 @@
+-- Create table for filters that decide which updates of the link are pushed
+create table link_filter (
+    id integer primary key autoincrement not null,
+    user_info_id integer not null references user_info(id),
+    kind text not null,
+    pattern text not null
+);
+
+create index link_filter_user_info on link_filter(user_info_id);
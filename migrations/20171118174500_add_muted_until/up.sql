@@ -0,0 +1,2 @@
+-- Allow links to be muted for a while without unlinking them
+alter table user_info add column muted_until datetime;
@@ -0,0 +1,2 @@
+-- undo creating table poll_schedule
+drop table poll_schedule;
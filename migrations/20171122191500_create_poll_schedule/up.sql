@@ -0,0 +1,11 @@
+-- Create table for polling schedule of downstream accounts, so it survives restarts
+create table poll_schedule (
+    id integer primary key autoincrement not null,
+    adapter text not null,
+    linked_user_id text not null,
+    next_poll datetime not null,
+    interval_secs integer not null,
+    latest_item datetime
+);
+
+create unique index poll_schedule_uniq on poll_schedule(adapter, linked_user_id);
@@ -0,0 +1,18 @@
+-- undo adding update contents to digest_item, SQLite can't drop columns so recreate the table
+create table digest_item_backup (
+    id integer primary key autoincrement not null,
+    user_info_id integer not null references user_info(id),
+    upstream_type text not null,
+    chat_id text not null,
+    linked_user_id text not null,
+    topic_id text not null,
+    title text not null,
+    url text not null,
+    queued_at datetime not null
+);
+
+insert into digest_item_backup select id, user_info_id, upstream_type, chat_id, linked_user_id, topic_id, title, url, queued_at from digest_item;
+drop table digest_item;
+alter table digest_item_backup rename to digest_item;
+
+create index digest_item_chat on digest_item(upstream_type, chat_id);
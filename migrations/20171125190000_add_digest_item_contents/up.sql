@@ -0,0 +1,11 @@
+-- Keep whole contents of held back updates, so ones held during quiet hours can be posted on their own later
+alter table digest_item add column held_for text not null default 'digest';
+alter table digest_item add column item_id text not null default '';
+alter table digest_item add column item_kind text not null default 'Post';
+alter table digest_item add column item_date datetime not null default '1970-01-01 00:00:00';
+alter table digest_item add column author text not null default '';
+alter table digest_item add column author_url text not null default '';
+alter table digest_item add column item_text text not null default '';
+alter table digest_item add column rich_text text;
+alter table digest_item add column shown_date text not null default '';
+alter table digest_item add column section text not null default '';
@@ -0,0 +1,2 @@
+-- undo creating table thread_root
+drop table thread_root;
@@ -0,0 +1,10 @@
+-- Create table for first messages of upstream threads updates are grouped into, so threads continue after restart
+create table thread_root (
+    id integer primary key autoincrement not null,
+    upstream_type text not null,
+    chat_id text not null,
+    thread_key text not null,
+    event_id text not null
+);
+
+create unique index thread_root_uniq on thread_root(upstream_type, chat_id, thread_key);
@@ -0,0 +1,2 @@
+-- undo creating table upstream_session
+drop table upstream_session;
@@ -0,0 +1,8 @@
+-- Create table for positions in event streams of upstreams, so commands aren't read again after restart
+create table upstream_session (
+    id integer primary key autoincrement not null,
+    upstream_type text not null,
+    sync_token text not null
+);
+
+create unique index upstream_session_uniq on upstream_session(upstream_type);
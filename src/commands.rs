@@ -0,0 +1,534 @@
+use std::result;
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use entities::*;
+use settings;
+use filters;
+use filters::Filters;
+
+/// Argument of the command, used for both parsing and help
+pub struct ArgSpec {
+    pub name: &'static str,
+    /// can be omitted
+    pub optional: bool,
+    /// takes the rest of the command text, must be last
+    pub rest: bool,
+}
+
+/// Builds upstream update from the command arguments or explains what's wrong with them
+type CommandBuilder = fn(&str, &Origin, &[&str]) -> result::Result<UpstreamUpdate, String>;
+
+/// Command that users can issue in upstream chats
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub args: &'static [ArgSpec],
+    /// one-liner for command list
+    pub summary: &'static str,
+    /// detailed description for `help <command>`
+    pub help: &'static str,
+    build: CommandBuilder,
+}
+
+/// All commands bot understands, upstreams only need to extract command text
+/// and supply their prefix
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "link",
+        args: &[
+            ArgSpec { name: "adapter", optional: false, rest: false },
+            ArgSpec { name: "user", optional: false, rest: false },
+            ArgSpec { name: "replies", optional: true, rest: false },
+        ],
+        summary: "link your downstream account to this chat",
+        help: "Starts linking your downstream account, e.g. LinuxOrgRu, to this chat. \
+               You'll be asked to prove that account is yours. Once verified, new posts of that account \
+               are reported here. Add 'replies' to be notified when somebody replies to you, \
+               linking the same account again with or without it turns that on or off.",
+        build: build_link,
+    },
+    CommandSpec {
+        name: "linkfor",
+        args: &[
+            ArgSpec { name: "member", optional: false, rest: false },
+            ArgSpec { name: "adapter", optional: false, rest: false },
+            ArgSpec { name: "user", optional: false, rest: false },
+            ArgSpec { name: "replies", optional: true, rest: false },
+        ],
+        summary: "link downstream account of other chat member to this chat",
+        help: "Same as link, but on behalf of other chat member. \
+               The account still has to be verified the usual way.",
+        build: build_link_for,
+    },
+    CommandSpec {
+        name: "unlink",
+        args: &[
+            ArgSpec { name: "adapter", optional: false, rest: false },
+            ArgSpec { name: "user", optional: false, rest: false },
+        ],
+        summary: "unlink your downstream account from this chat",
+        help: "Stops reporting posts of your downstream account to this chat.",
+        build: build_unlink,
+    },
+    CommandSpec {
+        name: "filter",
+        args: &[
+            ArgSpec { name: "adapter", optional: false, rest: false },
+            ArgSpec { name: "user", optional: false, rest: false },
+            ArgSpec { name: "action", optional: true, rest: false },
+            ArgSpec { name: "value", optional: true, rest: true },
+        ],
+        summary: "show or change which updates of your link are reported",
+        help: "Shows filters of your verified link or adds one. Actions are: \
+               include <keyword or /regex/> - report only updates that contain it, \
+               exclude <keyword or /regex/> - don't report updates that contain it, \
+               section <name> - report only updates from this section, e.g. talks, \
+               minlength <number> - report only updates with text at least this long, \
+               clear - remove all filters of the link.",
+        build: build_filter,
+    },
+    CommandSpec {
+        name: "mute",
+        args: &[
+            ArgSpec { name: "adapter", optional: false, rest: false },
+            ArgSpec { name: "user", optional: false, rest: false },
+            ArgSpec { name: "duration", optional: true, rest: false },
+        ],
+        summary: "stop reporting updates of your link for a while",
+        help: "Stops reporting updates of your verified link for the duration, e.g. 30m, 2h, 1d or 1w, \
+               at most a year, or until unmuted if duration is omitted. Updates made while link is muted are never reported.",
+        build: build_mute,
+    },
+    CommandSpec {
+        name: "unmute",
+        args: &[
+            ArgSpec { name: "adapter", optional: false, rest: false },
+            ArgSpec { name: "user", optional: false, rest: false },
+        ],
+        summary: "resume reporting updates of your muted link",
+        help: "Resumes reporting updates of your link that was muted. Updates made while it was muted are skipped.",
+        build: build_unmute,
+    },
+    CommandSpec {
+        name: "unlinkall",
+        args: &[
+            ArgSpec { name: "everywhere", optional: true, rest: false },
+            ArgSpec { name: "confirm", optional: true, rest: false },
+        ],
+        summary: "unlink all your accounts from this chat",
+        help: "Stops reporting posts of all your linked downstream accounts to this chat, \
+               or to all chats if 'everywhere' is added. Shows what would be unlinked first, \
+               add 'confirm' to actually unlink.",
+        build: build_unlink_all,
+    },
+    CommandSpec {
+        name: "explain",
+        args: &[ArgSpec { name: "command", optional: false, rest: true }],
+        summary: "explain shell command",
+        help: "Explains what each part of the shell command does, courtesy of mankier.com.",
+        build: build_explain,
+    },
+    CommandSpec {
+        name: "list",
+        args: &[],
+        summary: "show your links in this chat",
+        help: "Shows all your links in this chat and whether they're verified or still pending.",
+        build: build_list,
+    },
+    CommandSpec {
+        name: "status",
+        args: &[],
+        summary: "show status of links in this chat",
+        help: "Shows when each link in this chat was polled last time, when it last had updates \
+               and the errors that occurred while polling.",
+        build: build_status,
+    },
+    CommandSpec {
+        name: "permissions",
+        args: &[
+            ArgSpec { name: "action", optional: true, rest: false },
+            ArgSpec { name: "level", optional: true, rest: false },
+        ],
+        summary: "show or change who can do what in this chat",
+        help: "Shows minimum power levels required for actions in this chat or changes one of them. \
+               Actions are: link - link own accounts, link_others - link accounts on behalf of others, \
+               settings - change settings of this chat, including permissions.",
+        build: build_permissions,
+    },
+    CommandSpec {
+        name: "settings",
+        args: &[
+            ArgSpec { name: "name", optional: true, rest: false },
+            ArgSpec { name: "value", optional: true, rest: true },
+        ],
+        summary: "show or change settings of this chat",
+        help: "Shows all settings of this chat, describes the requested one or changes it. \
+               Use 'default' as value to reset the setting.",
+        build: build_settings,
+    },
+    CommandSpec {
+        name: "admin",
+        args: &[
+            ArgSpec { name: "action", optional: false, rest: false },
+            ArgSpec { name: "argument", optional: true, rest: false },
+        ],
+        summary: "bot-wide management, for operators only",
+        help: "Actions are: links - list all links, unlink <id> - remove link, \
+               verify <id> - verify link without proof, pause <adapter> - stop polling adapter, \
+               resume <adapter> - start polling it again, leave <chat> - leave chat and drop its links, \
+               ban <user> - ignore user's commands, unban <user> - lift the ban, stats - show statistics. \
+               Links are referred to by their ids shown in 'links' output.",
+        build: build_admin,
+    },
+    CommandSpec {
+        name: "help",
+        args: &[ArgSpec { name: "command", optional: true, rest: false }],
+        summary: "show this help",
+        help: "Shows list of commands or detailed help for the requested one.",
+        build: build_help,
+    },
+];
+
+/// Parse command text, stripped from upstream-specific prefix, into upstream update.
+///
+/// If text is not a valid command, returns `UpstreamUpdate::Invalid` with explanation for user.
+pub fn parse_command(upstream_type: &str, prefix: &str, origin: &Origin, text: &str) -> UpstreamUpdate {
+    let mut words = text.split_whitespace();
+    let name = match words.next() {
+        None => return UpstreamUpdate::Invalid { reason: format!("Empty command, try {}help", prefix) },
+        Some(name) => name,
+    };
+
+    let spec = match COMMANDS.iter().find(|c| c.name == name) {
+        None => return UpstreamUpdate::Invalid { reason: format!("Unknown command '{}', try {}help", name, prefix) },
+        Some(spec) => spec,
+    };
+
+    // if command takes the rest of the text, collapse it into one argument
+    let rest_text: String;
+    let mut args: Vec<&str> = words.collect();
+    if let Some(rest_pos) = spec.args.iter().position(|a| a.rest) {
+        if args.len() > rest_pos {
+            rest_text = args[rest_pos..].join(" ");
+            args.truncate(rest_pos);
+            args.push(&rest_text);
+        }
+    }
+
+    let required = spec.args.iter().filter(|a| !a.optional).count();
+    if args.len() < required || args.len() > spec.args.len() {
+        return UpstreamUpdate::Invalid { reason: format!("Usage: {}", usage(prefix, spec)) };
+    }
+
+    match (spec.build)(upstream_type, origin, &args) {
+        Ok(update) => update,
+        Err(reason) => UpstreamUpdate::Invalid { reason: format!("{}\nUsage: {}", reason, usage(prefix, spec)) },
+    }
+}
+
+/// Usage line for the command, e.g. `!link <adapter> <user> [replies]`
+pub fn usage(prefix: &str, spec: &CommandSpec) -> String {
+    let mut line = prefix.to_owned() + spec.name;
+    for arg in spec.args {
+        let name = if arg.rest { format!("{}...", arg.name) } else { arg.name.to_owned() };
+        if arg.optional {
+            line = line + " [" + &name + "]";
+        } else {
+            line = line + " <" + &name + ">";
+        }
+    }
+    line
+}
+
+/// Help text for all commands or the requested one
+pub fn help(prefix: &str, command: Option<&str>) -> String {
+    match command {
+        Some(name) => {
+            match COMMANDS.iter().find(|c| c.name == name.trim_left_matches(prefix)) {
+                None => format!("Unknown command '{}', try {}help", name, prefix),
+                Some(spec) => format!("{}\n{}", usage(prefix, spec), spec.help),
+            }
+        }
+        None => {
+            let lines: Vec<String> = COMMANDS.iter()
+                .map(|spec| format!("{} - {}", usage(prefix, spec), spec.summary))
+                .collect();
+            format!("Available commands:\n{}", lines.join("\n"))
+        }
+    }
+}
+
+/// Describe links of the user who issued the command in the chat command was issued in, along with their filters
+pub fn list_links(requests: &[UserInfo], filters: &HashMap<i32, Filters>, upstream_type: &str, origin: &Origin)
+                  -> String {
+    let lines: Vec<String> = requests.iter()
+        .filter(|i| i.upstream_type == upstream_type && i.chat_id == origin.chat_id && i.user_id == origin.user_id)
+        .map(|i| {
+            let state = if i.verified { "verified" } else { "pending verification" };
+            let mode = if i.track_replies { ", with replies" } else { "" };
+            let mut line = format!("{} {} - {}{}", i.adapter.to_string(), i.linked_user_id, state, mode);
+            match i.muted_until {
+                Some(until) if until == muted_forever() => line = line + ", muted",
+                Some(until) if i.is_muted(Utc::now().naive_utc()) => line = line + &format!(", muted until {}", until),
+                _ => {}
+            }
+            if let Some(link_filters) = filters.get(&i.id) {
+                line = line + &format!(", filters: {}", link_filters.describe());
+            }
+            line
+        })
+        .collect();
+
+    if lines.is_empty() {
+        return "You have no links in this chat".to_owned();
+    }
+    format!("Your links in this chat:\n{}", lines.join("\n"))
+}
+
+/// Describe polling status of all links in the chat command was issued in
+/// along with `problems` of sources bot uses
+pub fn link_status(requests: &[UserInfo], statuses: &HashMap<String, LinkStatus>, problems: &[String],
+                   upstream_type: &str, origin: &Origin) -> String {
+    let format_date = |date: Option<NaiveDateTime>| date.map_or("never".to_owned(), |d| d.to_string());
+
+    let lines: Vec<String> = requests.iter()
+        .filter(|i| i.upstream_type == upstream_type && i.chat_id == origin.chat_id)
+        .map(|i| {
+            let status = statuses.get(&i.key()).cloned().unwrap_or_default();
+            let last_update = if i.last_update.timestamp() == 0 { None } else { Some(i.last_update) };
+            let mut line = format!("{} {} (by {}): polled {}, last update {}",
+                                   i.adapter.to_string(),
+                                   i.linked_user_id,
+                                   i.user_id,
+                                   format_date(status.last_poll),
+                                   format_date(last_update));
+            if let Some(error) = status.last_error {
+                line = line + &format!(", {} errors in a row, last: {}", status.error_count, error);
+            }
+            line
+        })
+        .collect();
+
+    let mut status = if lines.is_empty() {
+        "There are no links in this chat".to_owned()
+    } else {
+        format!("Links in this chat:\n{}", lines.join("\n"))
+    };
+    if !problems.is_empty() {
+        status = status + &format!("\nSources with problems:\n{}", problems.join("\n"));
+    }
+    status
+}
+
+/// Which permission user needs to have in the chat to perform the command, if any
+pub fn required_permission(update: &UpstreamUpdate, origin: &Origin) -> Option<Permission> {
+    match *update {
+        UpstreamUpdate::Link(ref info) if info.user_id != origin.user_id => Some(Permission::LinkOthers),
+        UpstreamUpdate::Link(_) | UpstreamUpdate::Unlink(_) | UpstreamUpdate::UnlinkAll { .. } => {
+            Some(Permission::Link)
+        }
+        UpstreamUpdate::Filter { change: Some(_), .. } => Some(Permission::Link),
+        UpstreamUpdate::Mute { .. } | UpstreamUpdate::Unmute(_) => Some(Permission::Link),
+        UpstreamUpdate::Permissions { change: Some(_) } => Some(Permission::Settings),
+        UpstreamUpdate::Settings { value: Some(_), .. } => Some(Permission::Settings),
+        _ => None,
+    }
+}
+
+/// Describe minimum power levels required for actions in the chat
+pub fn describe_permissions(perms: &RoomPermissions) -> String {
+    let lines: Vec<String> = [Permission::Link, Permission::LinkOthers, Permission::Settings].iter()
+        .map(|p| format!("{} - {}", p.to_string(), perms.level_for(*p)))
+        .collect();
+    format!("Minimum power levels in this chat:\n{}", lines.join("\n"))
+}
+
+/// Build user info from command origin and `<adapter> <user>` arguments
+fn info_from_args(upstream_type: &str, origin: &Origin, args: &[&str]) -> result::Result<UserInfo, String> {
+    let adapter: Adapter = match str::parse(args[0]) {
+        Ok(adapter) => adapter,
+        Err(_) => return Err(format!("Unknown adapter '{}'", args[0])),
+    };
+
+    Ok(UserInfo {
+        id: 0,
+        upstream_type: upstream_type.to_owned(),
+        chat_id: origin.chat_id.to_owned(),
+        user_id: origin.user_id.to_owned(),
+        adapter: adapter,
+        linked_user_id: args[1].to_owned(),
+        last_update: NaiveDateTime::from_timestamp(0, 0),
+        verified: false,
+        track_replies: false,
+        muted_until: None,
+    })
+}
+
+fn build_link(upstream_type: &str, origin: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    let mut info = info_from_args(upstream_type, origin, args)?;
+    match args.get(2) {
+        None => {}
+        Some(&"replies") => info.track_replies = true,
+        Some(flag) => return Err(format!("Unknown flag '{}'", flag)),
+    }
+    Ok(UpstreamUpdate::Link(info))
+}
+
+fn build_link_for(upstream_type: &str, origin: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    let member = args[0];
+    match build_link(upstream_type, origin, &args[1..])? {
+        UpstreamUpdate::Link(mut info) => {
+            info.user_id = member.to_owned();
+            Ok(UpstreamUpdate::Link(info))
+        }
+        other => Ok(other),
+    }
+}
+
+fn build_unlink(upstream_type: &str, origin: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    info_from_args(upstream_type, origin, args).map(UpstreamUpdate::Unlink)
+}
+
+fn build_filter(upstream_type: &str, origin: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    let link = info_from_args(upstream_type, origin, args)?;
+    let change = match (args.get(2), args.get(3)) {
+        (None, _) => None,
+        (Some(&"clear"), None) => Some(FilterChange::Clear),
+        (Some(&"clear"), Some(_)) => return Err("Clear takes no value".to_owned()),
+        (Some(action), value) => {
+            let kind: FilterKind = match str::parse(action) {
+                Ok(kind) => kind,
+                Err(_) => return Err(format!("Unknown action '{}'", action)),
+            };
+            let value = match value {
+                None => return Err(format!("Action '{}' requires value", action)),
+                Some(value) => value,
+            };
+            filters::validate(kind, value)?;
+            Some(FilterChange::Add(kind, value.to_string()))
+        }
+    };
+    Ok(UpstreamUpdate::Filter { link: link, change: change })
+}
+
+fn build_mute(upstream_type: &str, origin: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    let link = info_from_args(upstream_type, origin, args)?;
+    let duration = match args.get(2) {
+        None => None,
+        Some(duration) => Some(parse_duration(duration)?),
+    };
+    Ok(UpstreamUpdate::Mute { link: link, duration: duration })
+}
+
+fn build_unmute(upstream_type: &str, origin: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    info_from_args(upstream_type, origin, args).map(UpstreamUpdate::Unmute)
+}
+
+/// Longest duration link can be muted for, longer mutes are until unmuted
+const MAX_MUTE_DAYS: i64 = 365;
+
+/// Parse duration like `30m`, `2h`, `1d` or `1w`, at most `MAX_MUTE_DAYS` long
+fn parse_duration(text: &str) -> result::Result<Duration, String> {
+    let invalid = || format!("Duration like 30m, 2h, 1d or 1w, at most a year, expected, got '{}'", text);
+    let unit = text.chars().last().ok_or_else(&invalid)?;
+    let amount: u32 = text[..text.len() - unit.len_utf8()].parse().map_err(|_| invalid())?;
+    let amount = i64::from(amount);
+    let duration = match unit {
+        'm' => Duration::minutes(amount),
+        'h' => Duration::hours(amount),
+        'd' => Duration::days(amount),
+        'w' => Duration::weeks(amount),
+        _ => return Err(invalid()),
+    };
+    if duration > Duration::days(MAX_MUTE_DAYS) {
+        return Err(invalid());
+    }
+    Ok(duration)
+}
+
+fn build_unlink_all(upstream_type: &str, origin: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    if let Some(flag) = args.iter().find(|a| **a != "everywhere" && **a != "confirm") {
+        return Err(format!("Unknown flag '{}'", flag));
+    }
+
+    let everywhere = args.contains(&"everywhere");
+    Ok(UpstreamUpdate::UnlinkAll {
+        upstream_type: upstream_type.to_owned(),
+        user_name: origin.user_id.to_owned(),
+        chat_id: if everywhere { None } else { Some(origin.chat_id.to_owned()) },
+        confirmed: args.contains(&"confirm"),
+    })
+}
+
+fn build_explain(_: &str, origin: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    Ok(UpstreamUpdate::Explain {
+        chat_id: origin.chat_id.to_owned(),
+        command: args[0].to_owned(),
+    })
+}
+
+fn build_list(_: &str, _: &Origin, _: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    Ok(UpstreamUpdate::List)
+}
+
+fn build_status(_: &str, _: &Origin, _: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    Ok(UpstreamUpdate::Status)
+}
+
+fn build_permissions(_: &str, _: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    if args.is_empty() {
+        return Ok(UpstreamUpdate::Permissions { change: None });
+    }
+    if args.len() < 2 {
+        return Err("Both action and level are required to change permissions".to_owned());
+    }
+
+    let permission: Permission = match str::parse(args[0]) {
+        Ok(permission) => permission,
+        Err(_) => return Err(format!("Unknown action '{}'", args[0])),
+    };
+    let level: i32 = match args[1].parse() {
+        Ok(level) => level,
+        Err(_) => return Err(format!("Level must be a number, got '{}'", args[1])),
+    };
+    Ok(UpstreamUpdate::Permissions { change: Some((permission, level)) })
+}
+
+fn build_settings(_: &str, _: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    let name = args.get(0).map(|n| n.to_string());
+    let value = args.get(1).map(|v| v.to_string());
+    if let (&Some(ref name), &Some(ref value)) = (&name, &value) {
+        if value != "default" {
+            settings::validate(name, value)?;
+        }
+    }
+    Ok(UpstreamUpdate::Settings { name: name, value: value })
+}
+
+fn build_admin(_: &str, _: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    let argument = args.get(1).map(|a| a.to_string());
+    let require_arg = |what: &str| argument.clone().ok_or_else(|| format!("This action requires {}", what));
+    let link_id = |arg: String| arg.trim_left_matches('#').parse::<i32>()
+        .map_err(|_| format!("Link id expected, got '{}'", arg));
+    let adapter = |arg: String| str::parse::<Adapter>(&arg).map_err(|_| format!("Unknown adapter '{}'", arg));
+
+    let command = match args[0] {
+        "links" => AdminCommand::Links,
+        "unlink" => AdminCommand::Unlink(link_id(require_arg("link id")?)?),
+        "verify" => AdminCommand::Verify(link_id(require_arg("link id")?)?),
+        "pause" => AdminCommand::Pause(adapter(require_arg("adapter")?)?),
+        "resume" => AdminCommand::Resume(adapter(require_arg("adapter")?)?),
+        "leave" => AdminCommand::Leave(require_arg("chat id")?),
+        "ban" => AdminCommand::Ban(require_arg("user id")?),
+        "unban" => AdminCommand::Unban(require_arg("user id")?),
+        "stats" => AdminCommand::Stats,
+        other => return Err(format!("Unknown action '{}'", other)),
+    };
+    Ok(UpstreamUpdate::Admin(command))
+}
+
+fn build_help(_: &str, _: &Origin, args: &[&str]) -> result::Result<UpstreamUpdate, String> {
+    Ok(UpstreamUpdate::Help { command: args.get(0).map(|c| c.to_string()) })
+}
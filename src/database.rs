@@ -1,3 +1,504 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use chrono::prelude::*;
+use diesel;
+use diesel::prelude::*;
+use diesel::result::OptionalExtension;
+use diesel::sqlite::SqliteConnection;
+
+use entities::*;
+use settings::RoomSettings;
+use filters::Filters;
+
 pub mod schema {
     infer_schema!("data/acc-linker-bot.db");
 }
+
+use self::schema::user_info;
+use self::schema::seen_item;
+use self::schema::room_permissions;
+use self::schema::banned_user;
+use self::schema::room_settings;
+use self::schema::digest_item;
+use self::schema::link_filter;
+use self::schema::poll_schedule;
+use self::schema::thread_root;
+use self::schema::upstream_session;
+
+/// Persist user info, inserting it if it was never saved and updating its state otherwise.
+/// Returns id it has in DB
+pub fn save_link(conn: &SqliteConnection, link: &UserInfo) -> Result<i32> {
+    if link.id != 0 {
+        // already saved, e.g. pending link saved on shutdown
+        diesel::update(user_info::table.filter(user_info::id.eq(link.id)))
+            .set((user_info::verified.eq(link.verified), user_info::last_update.eq(link.last_update)))
+            .execute(conn)?;
+        return Ok(link.id);
+    }
+
+    // screw you, Diesel
+    let new_row = NewUserInfo {
+        upstream_type: link.upstream_type.to_owned(),
+        chat_id: link.chat_id.to_owned(),
+        user_id: link.user_id.to_owned(),
+        adapter: link.adapter,
+        linked_user_id: link.linked_user_id.to_owned(),
+        last_update: link.last_update,
+        verified: link.verified,
+        track_replies: link.track_replies,
+    };
+
+    diesel::insert(&new_row).into(user_info::table).execute(conn)?;
+
+    // SQLite can't return inserted row, look it up by unique index
+    let id = user_info::table
+        .filter(user_info::upstream_type.eq(&link.upstream_type))
+        .filter(user_info::chat_id.eq(&link.chat_id))
+        .filter(user_info::user_id.eq(&link.user_id))
+        .filter(user_info::adapter.eq(&link.adapter))
+        .filter(user_info::linked_user_id.eq(&link.linked_user_id))
+        .select(user_info::id)
+        .first::<i32>(conn)?;
+    Ok(id)
+}
+
+/// Remove link along with everything remembered for it
+pub fn delete_link(conn: &SqliteConnection, link: &UserInfo) -> Result<()> {
+    if link.id == 0 {
+        // was never saved
+        return Ok(());
+    }
+
+    diesel::delete(seen_item::table.filter(seen_item::user_info_id.eq(link.id))).execute(conn)?;
+    diesel::delete(digest_item::table.filter(digest_item::user_info_id.eq(link.id))).execute(conn)?;
+    diesel::delete(link_filter::table.filter(link_filter::user_info_id.eq(link.id))).execute(conn)?;
+    diesel::delete(user_info::table.filter(user_info::id.eq(link.id))).execute(conn)?;
+    Ok(())
+}
+
+/// Seen item of a link that tells since when replies to linked user are tracked, see `set_track_replies`
+const REPLIES_SINCE: &str = "replies-tracked-since";
+
+/// Compare fetched items with items that were already delivered for this link and find out what changed.
+/// New items are remembered as delivered, edited ones get their content hash updated, deleted ones are forgotten.
+///
+/// If nothing was ever delivered for this link, all items are remembered and nothing is returned,
+/// we don't want to flood the chat with the whole history of the linked account.
+/// Same goes for replies made before their tracking was turned on for the link.
+///
+/// Adapters show only a limited number of recent posts, so absent post is considered deleted
+/// only if it's newer than the oldest post fetched. Posts adapter `skipped` as unparseable are still there.
+pub fn take_changes<'a>(conn: &SqliteConnection, link: &UserInfo, updates: Vec<&'a UpdateDesc>, skipped: &[String])
+                        -> Result<Vec<ItemChange<'a>>> {
+    let mut seen: HashMap<String, SeenItem> = seen_item::table
+        .filter(seen_item::user_info_id.eq(link.id))
+        .load::<SeenItem>(conn)?
+        .into_iter()
+        .map(|item| (item.item_id.to_owned(), item))
+        .collect();
+    let replies_since = seen.remove(REPLIES_SINCE).map(|marker| marker.item_date);
+    let first_time = seen.is_empty();
+
+    let oldest_post = updates.iter()
+        .filter(|u| u.kind() == UpdateKind::Post)
+        .map(|u| u.timestamp())
+        .min();
+    let mut fetched: HashSet<String> = HashSet::new();
+
+    let mut changes: Vec<ItemChange> = vec![];
+    for update in updates {
+        let item_id = update.id();
+        let content_hash = update.content_hash();
+        if !fetched.insert(item_id.to_owned()) {
+            // duplicate in the same batch
+            continue;
+        }
+
+        match seen.get(&item_id) {
+            None => {
+                let new_row = NewSeenItem {
+                    user_info_id: link.id,
+                    item_id: item_id.to_owned(),
+                    content_hash: content_hash,
+                    seen_at: Utc::now().naive_utc(),
+                    item_kind: update.kind().to_string(),
+                    item_date: update.timestamp(),
+                };
+                diesel::insert(&new_row).into(seen_item::table).execute(conn)?;
+
+                let old_reply = update.kind() == UpdateKind::Reply
+                    && replies_since.map_or(false, |since| update.timestamp() <= since);
+                if !first_time && !old_reply {
+                    changes.push(ItemChange::New(update));
+                }
+            }
+            Some(item) if item.content_hash != content_hash => {
+                diesel::update(seen_item::table.filter(seen_item::id.eq(item.id)))
+                    .set(seen_item::content_hash.eq(&content_hash))
+                    .execute(conn)?;
+
+                // items that were never pushed have nothing to edit,
+                // ones remembered by older versions just have their hash computed differently
+                let rehashed = update.old_content_hash().map_or(false, |old| old == item.content_hash);
+                if rehashed {
+                    continue;
+                }
+                if let Some(ref event_id) = item.event_id {
+                    changes.push(ItemChange::Edited { message_id: event_id.to_owned(), update: update });
+                }
+            }
+            Some(_) => {}
+        }
+    }
+
+    if first_time {
+        info!("Remembered {} initial items for {}", fetched.len(), link.linked_user_id);
+    }
+
+    let oldest_post = match oldest_post {
+        None => return Ok(changes),
+        Some(timestamp) => timestamp,
+    };
+
+    let post_kind = UpdateKind::Post.to_string();
+    for (item_id, item) in seen.drain() {
+        if fetched.contains(&item_id) || skipped.contains(&item_id) || item.item_kind != post_kind
+            || item.item_date <= oldest_post {
+            continue;
+        }
+
+        diesel::delete(seen_item::table.filter(seen_item::id.eq(item.id))).execute(conn)?;
+        if let Some(event_id) = item.event_id {
+            changes.push(ItemChange::Deleted { message_id: event_id });
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Remember which upstream message the item was pushed as, so it can be edited or deleted later
+pub fn remember_event(conn: &SqliteConnection, link: &UserInfo, item_id: &str, event_id: &str) -> Result<()> {
+    diesel::update(seen_item::table
+            .filter(seen_item::user_info_id.eq(link.id))
+            .filter(seen_item::item_id.eq(item_id)))
+        .set(seen_item::event_id.eq(Some(event_id.to_owned())))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Load permissions configured for the chat, defaults if they were never configured
+pub fn load_permissions(conn: &SqliteConnection, upstream_type: &str, chat_id: &str) -> Result<RoomPermissions> {
+    let saved = room_permissions::table
+        .filter(room_permissions::upstream_type.eq(upstream_type))
+        .filter(room_permissions::chat_id.eq(chat_id))
+        .first::<RoomPermissions>(conn)
+        .optional()?;
+    Ok(saved.unwrap_or_else(|| RoomPermissions::default_for(upstream_type, chat_id)))
+}
+
+/// Persist changed chat permissions
+pub fn save_permissions(conn: &SqliteConnection, perms: &RoomPermissions) -> Result<()> {
+    if perms.id == 0 {
+        let new_row = NewRoomPermissions {
+            upstream_type: perms.upstream_type.to_owned(),
+            chat_id: perms.chat_id.to_owned(),
+            link_level: perms.link_level,
+            link_others_level: perms.link_others_level,
+            settings_level: perms.settings_level,
+        };
+        diesel::insert(&new_row).into(room_permissions::table).execute(conn)?;
+        return Ok(());
+    }
+
+    diesel::update(room_permissions::table.filter(room_permissions::id.eq(perms.id)))
+        .set((room_permissions::link_level.eq(perms.link_level),
+              room_permissions::link_others_level.eq(perms.link_others_level),
+              room_permissions::settings_level.eq(perms.settings_level)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Check whether user is banned from using the bot in this upstream
+pub fn is_banned(conn: &SqliteConnection, upstream_type: &str, user_id: &str) -> Result<bool> {
+    let banned = banned_user::table
+        .filter(banned_user::upstream_type.eq(upstream_type))
+        .filter(banned_user::user_id.eq(user_id))
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(banned > 0)
+}
+
+/// Ban user from using the bot in this upstream, returns false if user was already banned
+pub fn ban_user(conn: &SqliteConnection, upstream_type: &str, user_id: &str) -> Result<bool> {
+    if is_banned(conn, upstream_type, user_id)? {
+        return Ok(false);
+    }
+
+    let new_row = NewBannedUser {
+        upstream_type: upstream_type.to_owned(),
+        user_id: user_id.to_owned(),
+        banned_at: Utc::now().naive_utc(),
+    };
+    diesel::insert(&new_row).into(banned_user::table).execute(conn)?;
+    Ok(true)
+}
+
+/// Lift the ban from the user in this upstream, returns false if user wasn't banned
+pub fn unban_user(conn: &SqliteConnection, upstream_type: &str, user_id: &str) -> Result<bool> {
+    let deleted = diesel::delete(banned_user::table
+            .filter(banned_user::upstream_type.eq(upstream_type))
+            .filter(banned_user::user_id.eq(user_id)))
+        .execute(conn)?;
+    Ok(deleted > 0)
+}
+
+/// Count of banned users and remembered items, for statistics
+pub fn count_stats(conn: &SqliteConnection) -> Result<(i64, i64)> {
+    let banned = banned_user::table.count().get_result::<i64>(conn)?;
+    let seen = seen_item::table.filter(seen_item::item_id.ne(REPLIES_SINCE)).count().get_result::<i64>(conn)?;
+    Ok((banned, seen))
+}
+
+/// Load settings of the chat, settings that were never changed have default values
+pub fn load_settings(conn: &SqliteConnection, upstream_type: &str, chat_id: &str) -> Result<RoomSettings> {
+    let values: HashMap<String, String> = room_settings::table
+        .filter(room_settings::upstream_type.eq(upstream_type))
+        .filter(room_settings::chat_id.eq(chat_id))
+        .load::<RoomSetting>(conn)?
+        .into_iter()
+        .map(|setting| (setting.name, setting.value))
+        .collect();
+    Ok(RoomSettings::new(values))
+}
+
+/// Change setting of the chat, `None` value resets it to default
+pub fn save_setting(conn: &SqliteConnection, upstream_type: &str, chat_id: &str, name: &str, value: Option<&str>)
+                    -> Result<()> {
+    let existing = room_settings::table
+        .filter(room_settings::upstream_type.eq(upstream_type))
+        .filter(room_settings::chat_id.eq(chat_id))
+        .filter(room_settings::name.eq(name));
+
+    match value {
+        None => {
+            diesel::delete(existing).execute(conn)?;
+        }
+        Some(value) => {
+            let updated = diesel::update(existing).set(room_settings::value.eq(value)).execute(conn)?;
+            if updated == 0 {
+                let new_row = NewRoomSetting {
+                    upstream_type: upstream_type.to_owned(),
+                    chat_id: chat_id.to_owned(),
+                    name: name.to_owned(),
+                    value: value.to_owned(),
+                };
+                diesel::insert(&new_row).into(room_settings::table).execute(conn)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hold the update back for the reason, it's sent later by itself or in a summary of the link's chat
+pub fn queue_digest_item(conn: &SqliteConnection, link: &UserInfo, update: &UpdateDesc, reason: HoldReason)
+                         -> Result<()> {
+    let fields = update.fields();
+    let new_row = NewDigestItem {
+        user_info_id: link.id,
+        upstream_type: link.upstream_type.to_owned(),
+        chat_id: link.chat_id.to_owned(),
+        linked_user_id: link.linked_user_id.to_owned(),
+        topic_id: update.topic_id(),
+        title: fields.title,
+        url: fields.url,
+        queued_at: Utc::now().naive_utc(),
+        held_for: reason.to_string(),
+        item_id: update.id(),
+        item_kind: update.kind().to_string(),
+        item_date: update.timestamp(),
+        author: fields.author,
+        author_url: fields.author_url,
+        item_text: fields.text,
+        rich_text: fields.rich_text,
+        shown_date: fields.date,
+        section: fields.section,
+    };
+    diesel::insert(&new_row).into(digest_item::table).execute(conn)?;
+    Ok(())
+}
+
+/// Load all updates waiting for digests, oldest first
+pub fn load_digest_items(conn: &SqliteConnection) -> Result<Vec<DigestItem>> {
+    let items = digest_item::table.order(digest_item::queued_at.asc()).load::<DigestItem>(conn)?;
+    Ok(items)
+}
+
+/// Forget updates that were sent in the digest
+pub fn delete_digest_items(conn: &SqliteConnection, ids: &[i32]) -> Result<()> {
+    diesel::delete(digest_item::table.filter(digest_item::id.eq_any(ids.to_vec()))).execute(conn)?;
+    Ok(())
+}
+
+/// Load filters of all links by link ids
+pub fn load_filters(conn: &SqliteConnection) -> Result<HashMap<i32, Filters>> {
+    let mut filters: HashMap<i32, Filters> = HashMap::new();
+    for row in link_filter::table.order(link_filter::id.asc()).load::<LinkFilter>(conn)? {
+        filters.entry(row.user_info_id).or_insert_with(Filters::default).add(&row);
+    }
+    Ok(filters)
+}
+
+/// Add filter to the link or clear all its filters, returns filters link has now
+pub fn change_filters(conn: &SqliteConnection, link: &UserInfo, change: &FilterChange) -> Result<Filters> {
+    match *change {
+        FilterChange::Add(kind, ref pattern) => {
+            let new_row = NewLinkFilter {
+                user_info_id: link.id,
+                kind: kind.to_string(),
+                pattern: pattern.to_owned(),
+            };
+            diesel::insert(&new_row).into(link_filter::table).execute(conn)?;
+        }
+        FilterChange::Clear => {
+            diesel::delete(link_filter::table.filter(link_filter::user_info_id.eq(link.id))).execute(conn)?;
+        }
+    }
+
+    let mut filters = Filters::default();
+    for row in link_filter::table.filter(link_filter::user_info_id.eq(link.id)).load::<LinkFilter>(conn)? {
+        filters.add(&row);
+    }
+    Ok(filters)
+}
+
+/// Persist the time link is muted until, `None` unmutes it
+pub fn set_muted(conn: &SqliteConnection, link: &UserInfo, until: Option<NaiveDateTime>) -> Result<()> {
+    diesel::update(user_info::table.filter(user_info::id.eq(link.id)))
+        .set(user_info::muted_until.eq(until))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Persist whether replies to the linked account are tracked
+///
+/// When tracking is turned on, replies made before that are remembered as seen once they're polled,
+/// so the chat doesn't get every old reply from the scanned threads at once
+pub fn set_track_replies(conn: &SqliteConnection, link: &UserInfo, track: bool) -> Result<()> {
+    if link.id == 0 {
+        // was never saved, saved with the rest of the link later
+        return Ok(());
+    }
+
+    diesel::update(user_info::table.filter(user_info::id.eq(link.id)))
+        .set(user_info::track_replies.eq(track))
+        .execute(conn)?;
+
+    if track && !link.track_replies {
+        diesel::delete(seen_item::table
+                .filter(seen_item::user_info_id.eq(link.id))
+                .filter(seen_item::item_id.eq(REPLIES_SINCE)))
+            .execute(conn)?;
+        let marker = NewSeenItem {
+            user_info_id: link.id,
+            item_id: REPLIES_SINCE.to_owned(),
+            content_hash: String::new(),
+            seen_at: Utc::now().naive_utc(),
+            item_kind: UpdateKind::Reply.to_string(),
+            item_date: Utc::now().naive_utc(),
+        };
+        diesel::insert(&marker).into(seen_item::table).execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Load polling schedule of all downstream accounts
+pub fn load_schedules(conn: &SqliteConnection) -> Result<Vec<PollSchedule>> {
+    let schedules = poll_schedule::table.load::<PollSchedule>(conn)?;
+    Ok(schedules)
+}
+
+/// Remove polling schedule of the account that isn't linked anymore
+pub fn delete_schedule(conn: &SqliteConnection, account: &(Adapter, String)) -> Result<()> {
+    diesel::delete(poll_schedule::table
+            .filter(poll_schedule::adapter.eq(&account.0))
+            .filter(poll_schedule::linked_user_id.eq(&account.1)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Persist polling schedule of the account, so it survives restarts
+pub fn save_schedule(conn: &SqliteConnection, schedule: &PollSchedule) -> Result<()> {
+    let updated = diesel::update(poll_schedule::table
+            .filter(poll_schedule::adapter.eq(&schedule.adapter))
+            .filter(poll_schedule::linked_user_id.eq(&schedule.linked_user_id)))
+        .set((poll_schedule::next_poll.eq(schedule.next_poll),
+              poll_schedule::interval_secs.eq(schedule.interval_secs),
+              poll_schedule::latest_item.eq(schedule.latest_item)))
+        .execute(conn)?;
+    if updated == 0 {
+        let new_row = NewPollSchedule {
+            adapter: schedule.adapter,
+            linked_user_id: schedule.linked_user_id.to_owned(),
+            next_poll: schedule.next_poll,
+            interval_secs: schedule.interval_secs,
+            latest_item: schedule.latest_item,
+        };
+        diesel::insert(&new_row).into(poll_schedule::table).execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Load first messages of upstream threads of this upstream type
+pub fn load_thread_roots(conn: &SqliteConnection, upstream_type: &str) -> Result<Vec<ThreadRoot>> {
+    let roots = thread_root::table
+        .filter(thread_root::upstream_type.eq(upstream_type))
+        .load::<ThreadRoot>(conn)?;
+    Ok(roots)
+}
+
+/// Persist first message of upstream thread, replacing the one thread had before
+pub fn save_thread_root(conn: &SqliteConnection, root: &NewThreadRoot) -> Result<()> {
+    let updated = diesel::update(thread_root::table
+            .filter(thread_root::upstream_type.eq(&root.upstream_type))
+            .filter(thread_root::chat_id.eq(&root.chat_id))
+            .filter(thread_root::thread_key.eq(&root.thread_key)))
+        .set(thread_root::event_id.eq(&root.event_id))
+        .execute(conn)?;
+    if updated == 0 {
+        diesel::insert(root).into(thread_root::table).execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Load where sync of the upstream stopped, `None` if it never synced
+pub fn load_sync_token(conn: &SqliteConnection, upstream_type: &str) -> Result<Option<String>> {
+    let session = upstream_session::table
+        .filter(upstream_session::upstream_type.eq(upstream_type))
+        .first::<UpstreamSession>(conn)
+        .optional()?;
+    Ok(session.map(|s| s.sync_token))
+}
+
+/// Persist where sync of the upstream stopped
+pub fn save_sync_token(conn: &SqliteConnection, upstream_type: &str, sync_token: &str) -> Result<()> {
+    let updated = diesel::update(upstream_session::table.filter(upstream_session::upstream_type.eq(upstream_type)))
+        .set(upstream_session::sync_token.eq(sync_token))
+        .execute(conn)?;
+    if updated == 0 {
+        let new_row = NewUpstreamSession {
+            upstream_type: upstream_type.to_owned(),
+            sync_token: sync_token.to_owned(),
+        };
+        diesel::insert(&new_row).into(upstream_session::table).execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Fresh in-memory DB with all migrations applied, for tests
+#[cfg(test)]
+pub fn test_connection() -> SqliteConnection {
+    let conn = SqliteConnection::establish(":memory:").expect("Must be able to open in-memory DB!");
+    diesel::migrations::run_pending_migrations(&conn).expect("Must be able to migrate in-memory DB!");
+    conn
+}
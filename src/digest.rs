@@ -0,0 +1,159 @@
+use entities::*;
+use settings::{Language, RoomSettings};
+use templates::{escape_html, TemplateFormat};
+
+/// Updates of one linked user in one topic
+struct TopicSummary<'a> {
+    title: &'a str,
+    /// link to the most recent update in the topic
+    url: &'a str,
+    count: usize,
+}
+
+/// Updates of one linked user, topics are in order of their first update
+struct UserSummary<'a> {
+    linked_user_id: &'a str,
+    topics: Vec<(&'a str, TopicSummary<'a>)>,
+}
+
+/// Summarize held back updates of the chat as one message, grouped by linked user and topic.
+/// Updates are summarized for periodic digests and when chat's rate limit is exceeded,
+/// ones held back during quiet hours are pushed one by one instead unless chat has no rate limit.
+///
+/// Only plain and HTML formats are supported, markdown dialects get plain text.
+pub fn render(items: &[DigestItem], format: TemplateFormat, settings: &RoomSettings) -> String {
+    let mut users: Vec<UserSummary> = vec![];
+    for item in items {
+        let found = users.iter().position(|u| u.linked_user_id == item.linked_user_id);
+        let user_idx = match found {
+            Some(idx) => idx,
+            None => {
+                users.push(UserSummary { linked_user_id: &item.linked_user_id, topics: vec![] });
+                users.len() - 1
+            }
+        };
+
+        let topics = &mut users[user_idx].topics;
+        let found = topics.iter().position(|&(topic_id, _)| topic_id == item.topic_id);
+        match found {
+            Some(idx) => {
+                let topic = &mut topics[idx].1;
+                topic.count += 1;
+                topic.url = &item.url;
+            }
+            None => topics.push((&item.topic_id, TopicSummary { title: &item.title, url: &item.url, count: 1 })),
+        }
+    }
+
+    let html = format == TemplateFormat::Html;
+    let periodic = settings.digest_period().is_some();
+    let mut message = headline(items.len(), periodic, settings.language());
+    message.push_str(if html { "<br/>" } else { "\n" });
+    for user in users {
+        let user_count: usize = user.topics.iter().map(|&(_, ref topic)| topic.count).sum();
+        if html {
+            message.push_str(&format!("<b>{}</b>: {}<ul>", escape_html(user.linked_user_id), user_count));
+            for (_, topic) in user.topics {
+                message.push_str(&format!("<li><a href='{}'>{}</a>: {}</li>",
+                                          escape_html(topic.url), escape_html(topic.title), topic.count));
+            }
+            message.push_str("</ul>");
+        } else {
+            message.push_str(&format!("{}: {}\n", user.linked_user_id, user_count));
+            for (_, topic) in user.topics {
+                message.push_str(&format!("\t{}: {} ({})\n", topic.title, topic.count, topic.url));
+            }
+        }
+    }
+    message
+}
+
+fn headline(count: usize, periodic: bool, language: Language) -> String {
+    match (periodic, language) {
+        (true, Language::English) => format!("Digest of {} updates:", count),
+        (true, Language::Russian) => format!("Сводка, обновлений: {}", count),
+        (false, Language::English) => format!("{} more updates:", count),
+        (false, Language::Russian) => format!("Ещё обновлений: {}", count),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn item(linked_user_id: &str, topic: u32, cid: u32) -> DigestItem {
+        let date = NaiveDate::from_ymd(2017, 11, 25).and_hms(12, 0, cid);
+        DigestItem {
+            id: cid as i32,
+            user_info_id: 1,
+            upstream_type: "Matrix".to_owned(),
+            chat_id: "!room".to_owned(),
+            linked_user_id: linked_user_id.to_owned(),
+            topic_id: format!("/forum/talks/{}", topic),
+            title: format!("Topic {}", topic),
+            url: format!("/forum/talks/{}?cid={}", topic, cid),
+            queued_at: date,
+            held_for: HoldReason::Digest.to_string(),
+            item_id: cid.to_string(),
+            item_kind: UpdateKind::Post.to_string(),
+            item_date: date,
+            author: linked_user_id.to_owned(),
+            author_url: String::new(),
+            item_text: "text".to_owned(),
+            rich_text: None,
+            shown_date: String::new(),
+            section: "talks".to_owned(),
+        }
+    }
+
+    /// Updates of alice and bob in two topics, in the order they were queued
+    fn items() -> Vec<DigestItem> {
+        vec![item("alice", 1, 1), item("bob", 2, 2), item("alice", 2, 3), item("alice", 1, 4), item("bob", 2, 5)]
+    }
+
+    fn settings(digest: &str, language: &str) -> RoomSettings {
+        let mut values = HashMap::new();
+        values.insert("digest".to_owned(), digest.to_owned());
+        values.insert("language".to_owned(), language.to_owned());
+        RoomSettings::new(values)
+    }
+
+    #[test]
+    fn groups_by_user_and_topic() {
+        let message = render(&items(), TemplateFormat::Plain, &settings("hourly", "en"));
+        // users and topics go in order of their first update, topics link to their latest one
+        assert_eq!(message, "Digest of 5 updates:\n\
+                             alice: 3\n\
+                             \tTopic 1: 2 (/forum/talks/1?cid=4)\n\
+                             \tTopic 2: 1 (/forum/talks/2?cid=3)\n\
+                             bob: 2\n\
+                             \tTopic 2: 2 (/forum/talks/2?cid=5)\n");
+    }
+
+    #[test]
+    fn renders_escaped_html() {
+        let mut items = items();
+        items[0].linked_user_id = "<alice>".to_owned();
+        items[0].title = "Topic <1>".to_owned();
+        let message = render(&items[..2], TemplateFormat::Html, &settings("off", "en"));
+        assert_eq!(message, "2 more updates:<br/>\
+                             <b>&lt;alice&gt;</b>: 1<ul>\
+                             <li><a href='/forum/talks/1?cid=1'>Topic &lt;1&gt;</a>: 1</li>\
+                             </ul>\
+                             <b>bob</b>: 1<ul>\
+                             <li><a href='/forum/talks/2?cid=2'>Topic 2</a>: 1</li>\
+                             </ul>");
+    }
+
+    #[test]
+    fn headline_is_translated() {
+        let message = render(&items(), TemplateFormat::Plain, &settings("daily", "ru"));
+        assert!(message.starts_with("Сводка, обновлений: 5\n"), "{}", message);
+        let message = render(&items(), TemplateFormat::Plain, &settings("off", "ru"));
+        assert!(message.starts_with("Ещё обновлений: 5\n"), "{}", message);
+    }
+}
@@ -4,7 +4,7 @@ use std::str;
 use std::str::FromStr;
 
 use chrono::prelude::*;
-use reqwest::Client;
+use futures::{future, Future};
 use config::Config;
 
 use diesel::expression::AsExpression;
@@ -14,8 +14,22 @@ use diesel::types::Text;
 use diesel::row::Row;
 use diesel::types::FromSqlRow;
 use database::schema::user_info;
+use database::schema::seen_item;
+use database::schema::room_permissions;
+use database::schema::banned_user;
+use database::schema::room_settings;
+use database::schema::digest_item;
+use database::schema::link_filter;
+use database::schema::poll_schedule;
+use database::schema::thread_root;
+use database::schema::upstream_session;
 
 use modules::*;
+use http::HttpClient;
+use modules::http_cache::HttpCache;
+use settings::{Language, RoomSettings};
+use templates;
+use templates::TemplateFormat;
 
 pub type Result<T> = result::Result<T, CoreError>;
 
@@ -30,24 +44,57 @@ pub enum CoreError {
     ConvertError(::std::io::Error),
     /// Error (de) serializing data
     JsonSerializeError(::serde_json::Error),
+    /// Error querying database
+    DatabaseError(::diesel::result::Error),
+    /// Error parsing date from downstream page
+    #[error(msg_embedded, non_std, no_from)]
+    DateError(String),
     /// Our own error
     #[error(msg_embedded, non_std, no_from)]
     CustomError(String),
 }
 
 /// Different markdown types for different upstreams
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MarkdownType {
     GitHub,
     Matrix,
+    /// Telegram MarkdownV2
     Telegram,
+    /// Slack mrkdwn, links are `<url|text>`
+    Slack,
+    Discord,
+    /// IRC formatting control codes, links are shown as is
+    Irc,
+}
+
+/// Where the command came from, so answers can be addressed properly
+#[derive(Debug, Clone)]
+pub struct Origin {
+    /// chat where command was issued
+    pub chat_id: String,
+    /// user who issued the command
+    pub user_id: String,
+    /// upstream id of the command message, if upstream supports replying to messages
+    pub message_id: Option<String>,
+}
+
+/// Command captured in upstream along with its origin
+#[derive(Debug)]
+pub struct UpstreamCommand {
+    pub origin: Origin,
+    pub update: UpstreamUpdate,
 }
 
 /// command syntax is e.g.:
 /// ```
 /// /link LinuxOrgRu username
+/// /link LinuxOrgRu username replies
 /// /unlink LinuxOrgRu username
-/// /unlinkall username
+/// /unlinkall
+/// /unlinkall everywhere confirm
 /// ```
+/// See `commands::COMMANDS` for the full list.
 #[derive(Debug)]
 pub enum UpstreamUpdate {
     /// Link user with his account
@@ -62,6 +109,10 @@ pub enum UpstreamUpdate {
         upstream_type: String,
         /// Which user to process unlink for
         user_name: String,
+        /// Chat to unlink from, all chats of this upstream if not set
+        chat_id: Option<String>,
+        /// Whether user confirmed it, if not, just show what would be unlinked
+        confirmed: bool,
     },
 
     /// Explain shell command
@@ -71,41 +122,349 @@ pub enum UpstreamUpdate {
         /// Command that was requested
         command: String
     },
+
+    /// Show list of commands or help for the requested one
+    Help {
+        /// Command to show help for, all commands if not set
+        command: Option<String>,
+    },
+
+    /// Show links of the user in the chat command came from
+    List,
+
+    /// Show polling status of links in the chat command came from
+    Status,
+
+    /// Show or change minimum power levels required for actions in the chat command came from
+    Permissions {
+        /// Action and new level for it, if not set just show current levels
+        change: Option<(Permission, i32)>,
+    },
+
+    /// Show or change filters of the link
+    Filter {
+        /// link to filter, identified the same way as for unlink
+        link: UserInfo,
+        /// what to change, filters are only shown if not set
+        change: Option<FilterChange>,
+    },
+
+    /// Stop reporting updates of the link for a while, or until unmuted if duration is not set
+    Mute {
+        link: UserInfo,
+        duration: Option<::chrono::Duration>,
+    },
+
+    /// Resume reporting updates of the muted link
+    Unmute(UserInfo),
+
+    /// Show or change settings of the chat command came from
+    Settings {
+        /// Setting to show or change, all settings are shown if not set
+        name: Option<String>,
+        /// New value for the setting, `default` resets it
+        value: Option<String>,
+    },
+
+    /// Bot-wide command only operators from config can issue
+    Admin(AdminCommand),
+
+    /// Command was not recognized or had wrong arguments
+    Invalid {
+        /// Explanation for the user
+        reason: String,
+    },
+}
+
+/// Bot-wide commands for operators. Links are referred to by their ids in DB, shown in `Links` output.
+#[derive(Debug)]
+pub enum AdminCommand {
+    /// List all links in all chats
+    Links,
+    /// Remove link regardless of who created it
+    Unlink(i32),
+    /// Mark link as verified without proof message
+    Verify(i32),
+    /// Stop polling adapter
+    Pause(Adapter),
+    /// Start polling paused adapter again
+    Resume(Adapter),
+    /// Leave chat and drop all links in it
+    Leave(String),
+    /// Ignore any commands from the user
+    Ban(String),
+    /// Lift the ban from the user
+    Unban(String),
+    /// Show global statistics
+    Stats,
+}
+
+/// Kinds of updates adapters can produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateKind {
+    /// Linked user posted something
+    Post,
+    /// Someone replied to linked user, should be addressed to the user in upstream
+    Reply,
+}
+
+impl ToString for UpdateKind {
+    fn to_string(&self) -> String {
+        match *self {
+            UpdateKind::Post => "Post".to_owned(),
+            UpdateKind::Reply => "Reply".to_owned(),
+        }
+    }
+}
+
+/// What happened with downstream item since it was last seen
+pub enum ItemChange<'a> {
+    /// Item was never seen before, it should be pushed
+    New(&'a UpdateDesc),
+    /// Item was pushed as upstream message before, but its contents changed since then
+    Edited {
+        /// id of the message in upstream
+        message_id: String,
+        /// new state of the item
+        update: &'a UpdateDesc,
+    },
+    /// Item was pushed as upstream message before, but now it's gone from downstream
+    Deleted {
+        /// id of the message in upstream
+        message_id: String,
+    },
+}
+
+/// Named fields of the update that message templates refer to, see `templates::FIELDS`
+#[derive(Debug, Clone, Default)]
+pub struct UpdateFields {
+    /// who made the update
+    pub author: String,
+    /// link to the author profile
+    pub author_url: String,
+    /// title of the topic update was made in
+    pub title: String,
+    /// link to the update itself
+    pub url: String,
+    /// contents of the update
+    pub text: String,
+    /// contents of the update with formatting, already sanitized.
+    /// Used instead of escaped `text` in HTML messages if present
+    pub rich_text: Option<String>,
+    /// when update was made, as shown to users
+    pub date: String,
+    /// section of the site topic belongs to, e.g. forum name
+    pub section: String,
+}
+
+impl UpdateFields {
+
+    /// Value of the field by its name in templates
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "author" => &self.author,
+            "author_url" => &self.author_url,
+            "title" => &self.title,
+            "url" => &self.url,
+            "text" => &self.text,
+            "date" => &self.date,
+            "section" => &self.section,
+            _ => return None,
+        };
+        Some(value.to_owned())
+    }
 }
 
 /// Update description, provides timestamp when update happened and various ways to
 /// represent it in upstreams.
-pub trait UpdateDesc {
-    fn as_string(&self) -> String;
-    fn as_markdown(&self, md_type: MarkdownType) -> String;
-    fn as_html(&self) -> String;
+///
+/// Representations are rendered from `fields` with built-in templates,
+/// upstreams use `templates::render_update` to apply templates configured in the chat.
+///
+/// Updates are polled on worker threads, hence `Send`.
+pub trait UpdateDesc: Send {
+    fn fields(&self) -> UpdateFields;
+
+    fn as_string(&self) -> String {
+        let template = templates::default_template(self.kind(), TemplateFormat::Plain, Language::English);
+        templates::render(&template, &self.fields(), TemplateFormat::Plain)
+    }
+
+    fn as_markdown(&self, md_type: MarkdownType) -> String {
+        let format = TemplateFormat::Markdown(md_type);
+        let template = templates::default_template(self.kind(), format, Language::English);
+        templates::render(&template, &self.fields(), format)
+    }
+
+    fn as_html(&self) -> String {
+        let template = templates::default_template(self.kind(), TemplateFormat::Html, Language::English);
+        templates::render(&template, &self.fields(), TemplateFormat::Html)
+    }
+
     fn timestamp(&self) -> NaiveDateTime;
+    fn kind(&self) -> UpdateKind;
+    /// Stable identifier of this item in downstream, e.g. LOR comment id
+    fn id(&self) -> String;
+    /// Identifier of the topic this item belongs to, e.g. LOR thread link
+    fn topic_id(&self) -> String;
+    /// Hash of the item contents, changes if the item is edited
+    fn content_hash(&self) -> String;
+    /// Hash older versions of the bot computed for the item, if it has changed since.
+    /// Items remembered with it are silently given the new hash instead of being reported as edited
+    fn old_content_hash(&self) -> Option<String> {
+        None
+    }
 }
 
 
 /// Where do we request updates to be sent to
 /// and from where do we connect to link accounts
-pub trait Upstream {
+///
+/// Unlike `Adapter::poll`, upstream calls are blocking and never made on the polling reactor,
+/// moving upstreams to the reactor is a separate piece of work.
+///
+/// Upstreams are synced on their own thread while dispatcher pushes updates to them, hence `Send + Sync`.
+/// Implementations lock their state only while accessing it, never for the duration of requests,
+/// so pushes don't wait for sync to finish and vice versa.
+pub trait Upstream: Send + Sync {
     /// Connect using credentials provided in config
-    fn connect(&mut self, client: &Client, cfg: &Config);
+    fn connect(&self, client: &HttpClient, cfg: &Config);
+
+    /// Close the session opened with `connect`, called once when bot shuts down
+    fn disconnect(&self, client: &HttpClient);
 
     /// Check updates that this upstream may have and return them
-    fn check_updates(&mut self, client: &Client) -> Result<Vec<UpstreamUpdate>>;
+    fn check_updates(&self, client: &HttpClient) -> Result<Vec<UpstreamCommand>>;
+
+    /// Where the next `check_updates` starts in the upstream event stream, saved so restarts continue from there
+    fn sync_token(&self) -> String;
 
-    /// Push formatted update from downstream adapter to this upstream
-    fn push_update(&self, client: &Client, chat_id: &str, update: Box<UpdateDesc>);
+    /// Push formatted update from downstream adapter to this upstream.
+    /// Link is provided so replies can be addressed to the upstream user,
+    /// settings of the link's chat determine how update is rendered
+    ///
+    /// Returns id of the posted message if it was posted successfully
+    fn push_update(&self, client: &HttpClient, link: &UserInfo, settings: &RoomSettings, update: &UpdateDesc)
+                   -> Option<String>;
+
+    /// Update that was pushed before as message with `message_id` was edited in downstream, edit it here too
+    fn edit_update(&self, client: &HttpClient, link: &UserInfo, settings: &RoomSettings, message_id: &str,
+                   update: &UpdateDesc);
+
+    /// Update that was pushed before as message with `message_id` was deleted in downstream, delete it here too
+    fn delete_update(&self, client: &HttpClient, link: &UserInfo, message_id: &str);
 
     /// User already requested this link or it already verified, report it
-    fn report_duplicate_link(&self, client: &Client, link: UserInfo);
+    fn report_duplicate_link(&self, client: &HttpClient, origin: &Origin, link: UserInfo);
 
     /// User requested this link, we should verify it in respective downstream, say that to user
-    fn report_link_to_verify(&self, client: &Client, link: &UserInfo);
+    fn report_link_to_verify(&self, client: &HttpClient, origin: &Origin, link: &UserInfo);
 
     /// User successfully verified this link, say that
-    fn report_added_link(&self, client: &Client, link: &UserInfo);
+    fn report_added_link(&self, client: &HttpClient, link: &UserInfo);
 
     /// Explain Linux shell command
-    fn explain_command(&self, client: &Client, chat_id: &str, command: &str);
+    fn explain_command(&self, client: &HttpClient, origin: &Origin, command: &str);
+
+    /// Answer the command with text message
+    fn reply(&self, client: &HttpClient, origin: &Origin, message: String);
+
+    /// What commands start with in this upstream, e.g. `!` or `/`
+    fn command_prefix(&self) -> &'static str;
+
+    /// Power level of the user in the chat, the higher the more user is allowed to do
+    fn power_level(&self, client: &HttpClient, chat_id: &str, user_id: &str) -> Result<i32>;
+
+    /// Leave the chat, no updates can be pushed there after that
+    fn leave_chat(&self, client: &HttpClient, chat_id: &str) -> Result<()>;
+
+    /// Push summary of updates held back for the chat, see `digest::render`
+    ///
+    /// Returns true if the digest was posted successfully
+    fn push_digest(&self, client: &HttpClient, chat_id: &str, settings: &RoomSettings, items: &[DigestItem]) -> bool;
+
+    /// Threads that pushed updates started since the last call, so they can be persisted
+    /// and continued after restart
+    fn take_new_thread_roots(&self) -> Vec<NewThreadRoot>;
+}
+
+/// Actions in the chat that may require elevated power level
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    /// Link own accounts to the chat
+    Link,
+    /// Link accounts on behalf of other chat members
+    LinkOthers,
+    /// Change chat settings, including permissions
+    Settings,
+}
+
+impl FromStr for Permission {
+    type Err = CoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "link" => Ok(Permission::Link),
+            "link_others" => Ok(Permission::LinkOthers),
+            "settings" => Ok(Permission::Settings),
+            _ => Err(CoreError::CustomError("No such permission!".to_owned())),
+        }
+    }
+}
+
+impl ToString for Permission {
+    fn to_string(&self) -> String {
+        match *self {
+            Permission::Link => "link".to_owned(),
+            Permission::LinkOthers => "link_others".to_owned(),
+            Permission::Settings => "settings".to_owned(),
+        }
+    }
+}
+
+/// What link filter checks in updates, see `filters::Filters`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterKind {
+    /// Only updates containing keyword or matching regex are pushed
+    Include,
+    /// Updates containing keyword or matching regex are not pushed
+    Exclude,
+    /// Only updates from these site sections are pushed
+    Section,
+    /// Only updates with text at least this long are pushed
+    MinLength,
+}
+
+impl FromStr for FilterKind {
+    type Err = CoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "include" => Ok(FilterKind::Include),
+            "exclude" => Ok(FilterKind::Exclude),
+            "section" => Ok(FilterKind::Section),
+            "minlength" => Ok(FilterKind::MinLength),
+            _ => Err(CoreError::CustomError("No such filter kind!".to_owned())),
+        }
+    }
+}
+
+impl ToString for FilterKind {
+    fn to_string(&self) -> String {
+        match *self {
+            FilterKind::Include => "include".to_owned(),
+            FilterKind::Exclude => "exclude".to_owned(),
+            FilterKind::Section => "section".to_owned(),
+            FilterKind::MinLength => "minlength".to_owned(),
+        }
+    }
+}
+
+/// Change of link filters requested with `filter` command
+#[derive(Debug)]
+pub enum FilterChange {
+    Add(FilterKind, String),
+    Clear,
 }
 
 /// Downstream where we retrieve updates from
@@ -152,24 +511,96 @@ impl FromSqlRow<Text, Sqlite> for Adapter {
 }
 
 
+/// How often adapter can be polled, see `scheduler::Scheduler`
+#[derive(Debug, Clone, Copy)]
+pub struct PollLimits {
+    /// active accounts are never polled more often than this
+    pub min_interval_secs: i32,
+    /// dormant accounts are never polled less often than this
+    pub max_interval_secs: i32,
+    /// how many requests per minute downstream can take from us
+    pub requests_per_minute: usize,
+}
+
 impl Adapter {
 
+    /// Polling limits of this downstream, so it's not hammered with requests
+    pub fn limits(&self) -> PollLimits {
+        match *self {
+            Adapter::LinuxOrgRu => PollLimits {
+                min_interval_secs: 60,
+                max_interval_secs: 60 * 60,
+                requests_per_minute: 12,
+            },
+        }
+    }
+
+    /// Host polls of this downstream go to, sources are considered down by their hosts
+    pub fn host(&self) -> &'static str {
+        match *self {
+            Adapter::LinuxOrgRu => lor_ru::LOR_HOST,
+        }
+    }
+
+    /// How many requests one poll of an account takes, at most
+    pub fn poll_cost(&self, with_replies: bool) -> usize {
+        match *self {
+            Adapter::LinuxOrgRu => if with_replies { 1 + lor_ru::MAX_SCANNED_THREADS } else { 1 },
+        }
+    }
+
     /// Poll data from this downstream adapter. This doesn't usually require any auth
     /// as you don't want to report your non-public posts to chats in upstreams
-    pub fn poll(&self, client: &Client, specifiers: Vec<String>) -> Result<Vec<Box<UpdateDesc>>> {
+    ///
+    /// Every account is polled once per cycle, results are shared by all links to it,
+    /// see `UserInfo::take_updates`. If `with_replies` is set, replies to the linked user are reported too.
+    /// Pages that didn't change since the last poll aren't parsed unless `always_parse` is set,
+    /// links waiting for verification need to see the updates account has, however old.
+    /// Polls run concurrently on the polling reactor, so they must not block. Pages are fetched through
+    /// the `cache` shared by all adapters
+    pub fn poll(&self, cache: &HttpCache, specifiers: Vec<String>, with_replies: bool, always_parse: bool)
+                -> Box<Future<Item = Polled, Error = CoreError>> {
         match *self {
             Adapter::LinuxOrgRu => {
                 let user_name = specifiers.into_iter().next().unwrap();
-                return lor_ru::get_user_posts(&user_name, client).map(|comments| {
-                    comments.into_iter()
-                        .map(|c| Box::new(c) as Box<UpdateDesc>)
-                        .collect()
+                let cache = cache.clone();
+                // replies are looked up in threads of the comments, so they need the page parsed
+                let always_parse = always_parse || with_replies;
+                let posts = lor_ru::get_user_posts(&user_name, &cache, always_parse);
+                let polled = posts.and_then(move |posts| -> Box<Future<Item = Polled, Error = CoreError>> {
+                    let lor_ru::UserPosts { comments, skipped } = match posts {
+                        None => return Box::new(future::ok(Polled::Unchanged)),
+                        Some(posts) => posts,
+                    };
+                    let replies: Box<Future<Item = Vec<lor_ru::LorReply>, Error = CoreError>> = if with_replies {
+                        lor_ru::get_user_replies(&user_name, &comments, &cache)
+                    } else {
+                        Box::new(future::ok(Vec::default()))
+                    };
+
+                    Box::new(replies.map(move |replies| {
+                        let mut updates: Vec<Box<UpdateDesc>> = comments.into_iter()
+                            .map(|c| Box::new(c) as Box<UpdateDesc>)
+                            .collect();
+                        updates.extend(replies.into_iter().map(|r| Box::new(r) as Box<UpdateDesc>));
+                        Polled::Updates { items: updates, skipped: skipped }
+                    }))
                 });
+                Box::new(polled)
             }
         }
     }
 }
 
+/// What polling downstream account found
+pub enum Polled {
+    /// account page didn't change since the last poll, so it wasn't parsed
+    Unchanged,
+    /// everything account has now, see `UserInfo::take_updates`.
+    /// Items that are there but couldn't be parsed are `skipped`, they aren't considered deleted
+    Updates { items: Vec<Box<UpdateDesc>>, skipped: Vec<String> },
+}
+
 /// User info struct, which provides a link between Connector and Adapter
 /// UserInfo struct instances are meant to be alive almost the same amount of time
 /// the application is running.
@@ -191,6 +622,10 @@ pub struct UserInfo {
     pub last_update: NaiveDateTime,
     /// Verified link with account or not
     pub verified: bool,
+    /// Whether to report replies to linked user
+    pub track_replies: bool,
+    /// Updates are not reported until this time, though polling goes on
+    pub muted_until: Option<NaiveDateTime>,
 }
 
 /// Diesel-requred insert helper
@@ -203,10 +638,376 @@ pub struct NewUserInfo {
     pub adapter: Adapter,
     pub linked_user_id: String,
     pub last_update: NaiveDateTime,
+    pub verified: bool,
+    pub track_replies: bool,
+}
+
+/// Minimum power levels required for actions in the chat
+#[derive(Debug, Clone, Queryable)]
+pub struct RoomPermissions {
+    /// internal id as saved in DB, 0 if permissions were never changed for this chat
+    pub id: i32,
+    pub upstream_type: String,
+    pub chat_id: String,
+    /// level required to link own accounts
+    pub link_level: i32,
+    /// level required to link accounts on behalf of others
+    pub link_others_level: i32,
+    /// level required to change chat settings
+    pub settings_level: i32,
+}
+
+/// Diesel-requred insert helper
+#[derive(Insertable)]
+#[table_name = "room_permissions"]
+pub struct NewRoomPermissions {
+    pub upstream_type: String,
+    pub chat_id: String,
+    pub link_level: i32,
+    pub link_others_level: i32,
+    pub settings_level: i32,
+}
+
+impl RoomPermissions {
+
+    /// Permissions for chat that never changed them, must be in sync with DB defaults
+    pub fn default_for(upstream_type: &str, chat_id: &str) -> RoomPermissions {
+        RoomPermissions {
+            id: 0,
+            upstream_type: upstream_type.to_owned(),
+            chat_id: chat_id.to_owned(),
+            link_level: 0,
+            link_others_level: 50,
+            settings_level: 50,
+        }
+    }
+
+    pub fn level_for(&self, permission: Permission) -> i32 {
+        match permission {
+            Permission::Link => self.link_level,
+            Permission::LinkOthers => self.link_others_level,
+            Permission::Settings => self.settings_level,
+        }
+    }
+
+    pub fn set_level(&mut self, permission: Permission, level: i32) {
+        match permission {
+            Permission::Link => self.link_level = level,
+            Permission::LinkOthers => self.link_others_level = level,
+            Permission::Settings => self.settings_level = level,
+        }
+    }
+}
+
+/// Setting value changed in the chat, see `settings::SETTINGS`
+#[derive(Debug, Queryable)]
+pub struct RoomSetting {
+    pub id: i32,
+    pub upstream_type: String,
+    pub chat_id: String,
+    pub name: String,
+    pub value: String,
+}
+
+/// Diesel-requred insert helper
+#[derive(Insertable)]
+#[table_name = "room_settings"]
+pub struct NewRoomSetting {
+    pub upstream_type: String,
+    pub chat_id: String,
+    pub name: String,
+    pub value: String,
+}
+
+/// User that is not allowed to use the bot
+#[derive(Debug, Queryable)]
+pub struct BannedUser {
+    pub id: i32,
+    pub upstream_type: String,
+    pub user_id: String,
+    pub banned_at: NaiveDateTime,
+}
+
+/// Diesel-requred insert helper
+#[derive(Insertable)]
+#[table_name = "banned_user"]
+pub struct NewBannedUser {
+    pub upstream_type: String,
+    pub user_id: String,
+    pub banned_at: NaiveDateTime,
+}
+
+/// Polling status of the user info, kept only in memory
+#[derive(Debug, Clone, Default)]
+pub struct LinkStatus {
+    /// when adapter was polled last time for this link
+    pub last_poll: Option<NaiveDateTime>,
+    /// last error that occurred while polling, cleared on successful poll
+    pub last_error: Option<String>,
+    /// how many polls in a row failed
+    pub error_count: u32,
+}
+
+/// Item that was already delivered for the user info
+#[derive(Debug, Queryable)]
+pub struct SeenItem {
+    pub id: i32,
+    /// user info this item was delivered for
+    pub user_info_id: i32,
+    /// item id as reported by `UpdateDesc::id`
+    pub item_id: String,
+    /// content hash as reported by `UpdateDesc::content_hash`
+    pub content_hash: String,
+    /// when this item was first seen
+    pub seen_at: NaiveDateTime,
+    /// id of upstream message this item was pushed as, if any
+    pub event_id: Option<String>,
+    /// item kind as reported by `UpdateDesc::kind`
+    pub item_kind: String,
+    /// item timestamp as reported by `UpdateDesc::timestamp`
+    pub item_date: NaiveDateTime,
+}
+
+/// Diesel-requred insert helper
+#[derive(Insertable)]
+#[table_name = "seen_item"]
+pub struct NewSeenItem {
+    pub user_info_id: i32,
+    pub item_id: String,
+    pub content_hash: String,
+    pub seen_at: NaiveDateTime,
+    pub item_kind: String,
+    pub item_date: NaiveDateTime,
+}
+
+/// When downstream account is going to be polled next time
+#[derive(Debug, Clone, Queryable)]
+pub struct PollSchedule {
+    pub id: i32,
+    pub adapter: Adapter,
+    pub linked_user_id: String,
+    pub next_poll: NaiveDateTime,
+    /// current interval between polls, shrinks while account is active and grows while it's dormant
+    pub interval_secs: i32,
+    /// timestamp of the most recent item account had, to tell whether it's active
+    pub latest_item: Option<NaiveDateTime>,
+}
+
+/// Diesel-requred insert helper
+#[derive(Insertable)]
+#[table_name = "poll_schedule"]
+pub struct NewPollSchedule {
+    pub adapter: Adapter,
+    pub linked_user_id: String,
+    pub next_poll: NaiveDateTime,
+    pub interval_secs: i32,
+    pub latest_item: Option<NaiveDateTime>,
+}
+
+/// First message of the upstream thread updates are grouped into
+#[derive(Debug, Clone, Queryable)]
+pub struct ThreadRoot {
+    pub id: i32,
+    pub upstream_type: String,
+    pub chat_id: String,
+    /// which updates go to the thread, e.g. ones of the same linked user
+    pub thread_key: String,
+    /// id of the message that started the thread
+    pub event_id: String,
+}
+
+/// Diesel-requred insert helper
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "thread_root"]
+pub struct NewThreadRoot {
+    pub upstream_type: String,
+    pub chat_id: String,
+    pub thread_key: String,
+    pub event_id: String,
+}
+
+/// Position in the event stream of the upstream, so commands aren't read again after restart
+#[derive(Debug, Clone, Queryable)]
+pub struct UpstreamSession {
+    pub id: i32,
+    pub upstream_type: String,
+    /// where the next sync starts, e.g. Matrix `next_batch`
+    pub sync_token: String,
+}
+
+/// Diesel-requred insert helper
+#[derive(Insertable)]
+#[table_name = "upstream_session"]
+pub struct NewUpstreamSession {
+    pub upstream_type: String,
+    pub sync_token: String,
+}
+
+/// Filter of the link, see `filters::Filters`
+#[derive(Debug, Queryable)]
+pub struct LinkFilter {
+    pub id: i32,
+    pub user_info_id: i32,
+    /// kind as in `FilterKind::to_string`
+    pub kind: String,
+    pub pattern: String,
+}
+
+/// Diesel-requred insert helper
+#[derive(Insertable)]
+#[table_name = "link_filter"]
+pub struct NewLinkFilter {
+    pub user_info_id: i32,
+    pub kind: String,
+    pub pattern: String,
+}
+
+/// Why update was held back instead of being pushed right away
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HoldReason {
+    /// chat gets updates as periodic digests
+    Digest,
+    /// update came during quiet hours of the chat, it's pushed on its own once they're over,
+    /// or summarized with the others if chat has no rate limit
+    QuietHours,
+    /// rate limit of the chat was exceeded, update goes to the summary of the ones that didn't fit
+    RateLimit,
+}
+
+impl FromStr for HoldReason {
+    type Err = CoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "digest" => Ok(HoldReason::Digest),
+            "quiet_hours" => Ok(HoldReason::QuietHours),
+            "rate_limit" => Ok(HoldReason::RateLimit),
+            _ => Err(CoreError::CustomError("No such hold reason!".to_owned())),
+        }
+    }
+}
+
+impl ToString for HoldReason {
+    fn to_string(&self) -> String {
+        match *self {
+            HoldReason::Digest => "digest".to_owned(),
+            HoldReason::QuietHours => "quiet_hours".to_owned(),
+            HoldReason::RateLimit => "rate_limit".to_owned(),
+        }
+    }
+}
+
+/// Update that is held back until the next digest of the chat, end of quiet hours or rate limit.
+/// Whole update is kept, so it can be pushed on its own later
+#[derive(Debug, Queryable)]
+pub struct DigestItem {
+    pub id: i32,
+    /// user info this item was found for
+    pub user_info_id: i32,
+    pub upstream_type: String,
+    pub chat_id: String,
+    pub linked_user_id: String,
+    /// topic as reported by `UpdateDesc::topic_id`, items are grouped by it
+    pub topic_id: String,
+    pub title: String,
+    pub url: String,
+    /// when this item was put aside for digest
+    pub queued_at: NaiveDateTime,
+    /// reason as in `HoldReason::to_string`
+    pub held_for: String,
+    /// id as reported by `UpdateDesc::id`
+    pub item_id: String,
+    /// kind as in `UpdateKind::to_string`
+    pub item_kind: String,
+    pub item_date: NaiveDateTime,
+    pub author: String,
+    pub author_url: String,
+    pub item_text: String,
+    pub rich_text: Option<String>,
+    /// date as shown to users, see `UpdateFields::date`
+    pub shown_date: String,
+    pub section: String,
+}
+
+impl DigestItem {
+
+    /// Why this item was held back, items saved before reasons were kept are from digests
+    pub fn hold_reason(&self) -> HoldReason {
+        str::parse(&self.held_for).unwrap_or(HoldReason::Digest)
+    }
+}
+
+impl UpdateDesc for DigestItem {
+    fn fields(&self) -> UpdateFields {
+        UpdateFields {
+            author: self.author.to_owned(),
+            author_url: self.author_url.to_owned(),
+            title: self.title.to_owned(),
+            url: self.url.to_owned(),
+            text: self.item_text.to_owned(),
+            rich_text: self.rich_text.to_owned(),
+            date: self.shown_date.to_owned(),
+            section: self.section.to_owned(),
+        }
+    }
+
+    fn timestamp(&self) -> NaiveDateTime {
+        self.item_date
+    }
+
+    fn kind(&self) -> UpdateKind {
+        if self.item_kind == UpdateKind::Reply.to_string() {
+            UpdateKind::Reply
+        } else {
+            UpdateKind::Post
+        }
+    }
+
+    fn id(&self) -> String {
+        self.item_id.to_owned()
+    }
+
+    fn topic_id(&self) -> String {
+        self.topic_id.to_owned()
+    }
+
+    fn content_hash(&self) -> String {
+        content_hash(&[&self.author, &self.title, &self.item_text])
+    }
+}
+
+/// Diesel-requred insert helper
+#[derive(Insertable)]
+#[table_name = "digest_item"]
+pub struct NewDigestItem {
+    pub user_info_id: i32,
+    pub upstream_type: String,
+    pub chat_id: String,
+    pub linked_user_id: String,
+    pub topic_id: String,
+    pub title: String,
+    pub url: String,
+    pub queued_at: NaiveDateTime,
+    pub held_for: String,
+    pub item_id: String,
+    pub item_kind: String,
+    pub item_date: NaiveDateTime,
+    pub author: String,
+    pub author_url: String,
+    pub item_text: String,
+    pub rich_text: Option<String>,
+    pub shown_date: String,
+    pub section: String,
+}
+
+/// Time links muted until explicitly unmuted are muted until
+pub fn muted_forever() -> NaiveDateTime {
+    NaiveDate::from_ymd(9999, 12, 31).and_hms(0, 0, 0)
 }
 
 impl PartialEq for UserInfo {
-    /// We don't compare internal ids and last_update times
+    /// We don't compare internal ids and last_update times. Whether replies are tracked is a setting
+    /// of the link, not part of its identity, linking again with different `replies` changes it
     fn eq(&self, rhs: &UserInfo) -> bool {
         self.chat_id == rhs.chat_id && self.user_id == rhs.user_id && self.linked_user_id == rhs.linked_user_id &&
         self.upstream_type == rhs.upstream_type && self.adapter == rhs.adapter
@@ -215,53 +1016,44 @@ impl PartialEq for UserInfo {
 
 impl UserInfo {
 
-    /// Retrieve info from adapter and update self from that info
-    /// * Don't report initial data, report only updates after that
+    /// Whether updates of this link shouldn't be reported right now
+    pub fn is_muted(&self, now: NaiveDateTime) -> bool {
+        self.muted_until.map_or(false, |until| until > now)
+    }
+
+    /// Key that identifies this link, same as `PartialEq` does
+    pub fn key(&self) -> String {
+        format!("{}/{}/{}/{}/{}", self.upstream_type, self.chat_id, self.user_id,
+                self.adapter.to_string(), self.linked_user_id)
+    }
+
+    /// Take updates adapter fetched for the linked account and update self from them
+    /// * Returns items relevant for this link, use `database::take_changes`
+    ///   to find out what's new since last time
     /// * If the message contains 'I love lor-bot!' then mark self as verified
-    pub fn poll(&mut self, client: &Client) -> Vec<Box<UpdateDesc>> {
-        let linked_user_name = self.linked_user_id.to_owned();
-        let update_result = self.adapter.poll(client, vec![linked_user_name]);
-        let updates = match update_result {
-            Err(error) => {
-                error!("Error while polling: {}", error.description());
-                return Vec::default();
-            }
-            Ok(updates) => {
-                if updates.is_empty() {
-                    info!("Nothing found for {}...", self.linked_user_id);
-                    return Vec::default();
-                }
-                updates
-            }
-        };
+    /// * Replies are only taken by verified links that track them, nobody else can verify it for user
+    pub fn take_updates<'a>(&mut self, updates: &'a [Box<UpdateDesc>]) -> Vec<&'a UpdateDesc> {
+        let with_replies = self.verified && self.track_replies;
+        let updates: Vec<&UpdateDesc> = updates.iter()
+            .map(|u| &**u)
+            .filter(|u| with_replies || u.kind() == UpdateKind::Post)
+            .collect();
+        if updates.is_empty() {
+            info!("Nothing found for {}...", self.linked_user_id);
+            return updates;
+        }
 
         // try to lookup proof message in adapter
         if !self.verified {
-            self.verified = updates.iter().any(|u| u.as_string().contains(CHALLENGE));
+            self.verified = updates.iter()
+                .filter(|u| u.kind() == UpdateKind::Post)
+                .any(|u| u.as_string().contains(CHALLENGE));
         }
 
         let current_latest_update = updates.iter().map(|u| u.timestamp()).max().unwrap();
-        if self.last_update == current_latest_update {
-            info!("No updates for {}...", self.linked_user_id);
-            return Vec::default();
-        }
-
-        // if we have last_update set to zero then this we are newly created user_info
-        // in this case, fetch all updates from the adapter and don't report them,
-        // instead, skip all the updates and set our timestamp to newest
-        if self.last_update.timestamp() == 0 {
-            info!("Updating newly created user info timestamp to latest available: {}",
-                  current_latest_update);
+        if self.last_update < current_latest_update {
             self.last_update = current_latest_update;
-            return Vec::default();
         }
-
-        // we got updates since last times, return them and update our last known timestamp
-        let new_updates: Vec<Box<UpdateDesc>> =
-            updates.into_iter().filter(|u| u.timestamp() > self.last_update).collect();
-        self.last_update = current_latest_update;
-
-        info!("Found {} updates for {}", new_updates.len(), self.linked_user_id);
-        new_updates
+        updates
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,245 @@
+use std::result;
+use std::str;
+
+use regex::Regex;
+
+use entities::*;
+
+/// What include or exclude filter looks for in update text
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// case-insensitive substring, stored lowercased
+    Keyword(String),
+    /// regex, written as `/pattern/`
+    Regex(Regex),
+}
+
+impl Matcher {
+
+    /// Parse filter pattern, `/pattern/` is treated as regex, anything else as keyword
+    fn parse(pattern: &str) -> result::Result<Matcher, String> {
+        if pattern.len() > 2 && pattern.starts_with('/') && pattern.ends_with('/') {
+            let regex = Regex::new(&pattern[1..pattern.len() - 1])
+                .map_err(|error| format!("Invalid regex '{}': {}", pattern, error))?;
+            return Ok(Matcher::Regex(regex));
+        }
+        Ok(Matcher::Keyword(pattern.to_lowercase()))
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        match *self {
+            Matcher::Keyword(ref keyword) => text.to_lowercase().contains(keyword.as_str()),
+            Matcher::Regex(ref regex) => regex.is_match(text),
+        }
+    }
+}
+
+/// Filters of one link, decide which of its new updates are pushed to the chat.
+///
+/// Update is pushed if it matches any include filter (or there are none), doesn't match
+/// any exclude filter, is from one of the sections (or there are none) and its text is long enough.
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    include: Vec<Matcher>,
+    exclude: Vec<Matcher>,
+    sections: Vec<String>,
+    min_length: usize,
+    /// filters as they were set, for `list` and `filter` commands
+    descriptions: Vec<String>,
+}
+
+impl Filters {
+
+    /// Add filter loaded from DB, filters that can't be parsed anymore are skipped
+    pub fn add(&mut self, row: &LinkFilter) {
+        let kind = match str::parse::<FilterKind>(&row.kind) {
+            Ok(kind) => kind,
+            Err(_) => {
+                warn!("Unknown kind of filter #{}: {}", row.id, row.kind);
+                return;
+            }
+        };
+
+        let added = match kind {
+            FilterKind::Include => Matcher::parse(&row.pattern).map(|m| self.include.push(m)),
+            FilterKind::Exclude => Matcher::parse(&row.pattern).map(|m| self.exclude.push(m)),
+            FilterKind::Section => {
+                self.sections.push(row.pattern.to_lowercase());
+                Ok(())
+            }
+            FilterKind::MinLength => {
+                row.pattern.parse::<usize>()
+                    .map(|length| self.min_length = length)
+                    .map_err(|_| format!("Invalid length '{}'", row.pattern))
+            }
+        };
+        match added {
+            Ok(_) => self.descriptions.push(format!("{} {}", row.kind, row.pattern)),
+            Err(error) => warn!("Skipping filter #{}: {}", row.id, error),
+        }
+    }
+
+    /// Whether update passes all filters and should be pushed
+    pub fn accepts(&self, update: &UpdateDesc) -> bool {
+        let fields = update.fields();
+        let text = format!("{}\n{}", fields.title, fields.text);
+        (self.include.is_empty() || self.include.iter().any(|m| m.matches(&text))) &&
+        !self.exclude.iter().any(|m| m.matches(&text)) &&
+        (self.sections.is_empty() || self.sections.contains(&fields.section.to_lowercase())) &&
+        fields.text.chars().count() >= self.min_length
+    }
+
+    /// Filters as they were set, comma-separated, or note that there are none
+    pub fn describe(&self) -> String {
+        if self.descriptions.is_empty() {
+            return "no filters".to_owned();
+        }
+        self.descriptions.join(", ")
+    }
+}
+
+/// Check filter before it's saved, explains what's wrong with it if it can't be used
+pub fn validate(kind: FilterKind, pattern: &str) -> result::Result<(), String> {
+    match kind {
+        FilterKind::Include | FilterKind::Exclude => Matcher::parse(pattern).map(|_| ()),
+        FilterKind::Section => Ok(()),
+        FilterKind::MinLength => {
+            pattern.parse::<usize>().map(|_| ()).map_err(|_| format!("Number expected, got '{}'", pattern))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+
+    struct Post {
+        title: &'static str,
+        text: &'static str,
+        section: &'static str,
+    }
+
+    impl UpdateDesc for Post {
+        fn fields(&self) -> UpdateFields {
+            UpdateFields {
+                title: self.title.to_owned(),
+                text: self.text.to_owned(),
+                section: self.section.to_owned(),
+                ..Default::default()
+            }
+        }
+
+        fn timestamp(&self) -> NaiveDateTime {
+            NaiveDateTime::from_timestamp(0, 0)
+        }
+
+        fn kind(&self) -> UpdateKind {
+            UpdateKind::Post
+        }
+
+        fn id(&self) -> String {
+            "1".to_owned()
+        }
+
+        fn topic_id(&self) -> String {
+            self.title.to_owned()
+        }
+
+        fn content_hash(&self) -> String {
+            String::new()
+        }
+    }
+
+    fn post(title: &'static str, text: &'static str) -> Post {
+        Post { title: title, text: text, section: "talks" }
+    }
+
+    fn filters_of(rows: &[(&str, &str)]) -> Filters {
+        let mut filters = Filters::default();
+        for (id, &(kind, pattern)) in rows.iter().enumerate() {
+            filters.add(&LinkFilter {
+                id: id as i32,
+                user_info_id: 1,
+                kind: kind.to_owned(),
+                pattern: pattern.to_owned(),
+            });
+        }
+        filters
+    }
+
+    #[test]
+    fn no_filters_accept_everything() {
+        let filters = filters_of(&[]);
+        assert!(filters.accepts(&post("", "")));
+        assert_eq!(filters.describe(), "no filters");
+    }
+
+    #[test]
+    fn keywords_ignore_case() {
+        let filters = filters_of(&[("include", "Rust")]);
+        assert!(filters.accepts(&post("Topic", "I like rust")));
+        assert!(filters.accepts(&post("Topic", "RUSTACEANS")));
+        // title counts too
+        assert!(filters.accepts(&post("Rust 1.21 released", "nice")));
+        assert!(!filters.accepts(&post("Topic", "I like go")));
+    }
+
+    #[test]
+    fn regexes_are_case_sensitive() {
+        let filters = filters_of(&[("include", "/Ru+st/")]);
+        assert!(filters.accepts(&post("Topic", "Ruuust")));
+        assert!(!filters.accepts(&post("Topic", "rust")));
+
+        let filters = filters_of(&[("include", "/(?i)^rust$/")]);
+        assert!(!filters.accepts(&post("Topic", "RUST")), "text follows the title");
+        let filters = filters_of(&[("include", "/(?im)^rust$/")]);
+        assert!(filters.accepts(&post("Topic", "RUST")));
+
+        // slashes alone don't make a regex
+        let filters = filters_of(&[("include", "//")]);
+        assert!(filters.accepts(&post("Topic", "see https://example.com")));
+        assert!(!filters.accepts(&post("Topic", "anything")));
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let filters = filters_of(&[("include", "rust"), ("exclude", "/[Jj]ava/")]);
+        assert!(filters.accepts(&post("Topic", "rust")));
+        assert!(!filters.accepts(&post("Topic", "rust vs Java")));
+        assert!(!filters.accepts(&post("Java news", "rust")));
+        assert_eq!(filters.describe(), "include rust, exclude /[Jj]ava/");
+    }
+
+    #[test]
+    fn sections_ignore_case() {
+        let filters = filters_of(&[("section", "Talks"), ("section", "development")]);
+        assert!(filters.accepts(&post("Topic", "text")));
+        assert!(filters.accepts(&Post { section: "Development", ..post("Topic", "text") }));
+        assert!(!filters.accepts(&Post { section: "general", ..post("Topic", "text") }));
+    }
+
+    #[test]
+    fn min_length_counts_chars_of_text() {
+        let filters = filters_of(&[("minlength", "6")]);
+        assert!(filters.accepts(&post("", "привет")));
+        assert!(filters.accepts(&post("", "hello, world")));
+        // title doesn't count
+        assert!(!filters.accepts(&post("Long enough title", "hello")));
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        assert!(validate(FilterKind::Include, "/(unclosed/").unwrap_err().starts_with("Invalid regex '/(unclosed/'"));
+        assert!(validate(FilterKind::Exclude, "/[/").is_err());
+        assert!(validate(FilterKind::Include, "(unclosed").is_ok());
+        assert!(validate(FilterKind::MinLength, "ten").is_err());
+        assert!(validate(FilterKind::MinLength, "10").is_ok());
+
+        // the ones that got into DB anyway are skipped
+        let filters = filters_of(&[("include", "/(unclosed/"), ("exclude", "spam"), ("unknown", "x")]);
+        assert!(filters.accepts(&post("Topic", "anything")));
+        assert_eq!(filters.describe(), "exclude spam");
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::mem;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use modules::DATE_FORMAT;
+
+/// State of the circuit of one source
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CircuitState {
+    /// source works, requests go through
+    Closed,
+    /// source is down, it's left alone until it's time to probe it
+    Open { probe_at: NaiveDateTime },
+    /// source is down and one probe request is in flight since then
+    HalfOpen { probed_at: NaiveDateTime },
+}
+
+/// Health of one source
+#[derive(Debug, Clone)]
+struct Circuit {
+    state: CircuitState,
+    /// how many requests in a row failed
+    failures: u32,
+    last_error: Option<String>,
+    /// when the circuit opened
+    down_since: Option<NaiveDateTime>,
+}
+
+/// Health of hosts adapters and upstreams talk to, by host names.
+///
+/// After `threshold` failed requests in a row circuit of the host opens, and no requests are made to it
+/// for the probe interval. Then one probe request is let through, success closes the circuit
+/// and failure opens it again. Probe that never reported back is replaced with another one after the probe
+/// interval. Notices about hosts that went down or recovered are queued, so operators can be notified.
+pub struct Health {
+    circuits: HashMap<String, Circuit>,
+    threshold: u32,
+    probe_interval: Duration,
+    /// notices operators weren't told about yet
+    notices: Vec<String>,
+}
+
+impl Health {
+
+    pub fn new(threshold: u32, probe_interval: Duration) -> Health {
+        Health {
+            circuits: HashMap::new(),
+            threshold: threshold.max(1),
+            probe_interval: probe_interval,
+            notices: vec![],
+        }
+    }
+
+    /// Whether source is down and isn't going to be probed right now
+    pub fn is_blocked(&self, source: &str, now: NaiveDateTime) -> bool {
+        match self.circuits.get(source).map(|c| c.state) {
+            None | Some(CircuitState::Closed) => false,
+            Some(CircuitState::Open { probe_at }) => probe_at > now,
+            Some(CircuitState::HalfOpen { probed_at }) => probed_at + self.probe_interval > now,
+        }
+    }
+
+    /// Whether request to the source can be made now. Once it's time to probe the source that is down,
+    /// this lets exactly one request through, result of the request must be reported with `succeeded` or `failed`
+    pub fn allows(&mut self, source: &str, now: NaiveDateTime) -> bool {
+        let probe_interval = self.probe_interval;
+        let circuit = match self.circuits.get_mut(source) {
+            None => return true,
+            Some(circuit) => circuit,
+        };
+        let probe = match circuit.state {
+            CircuitState::Closed => return true,
+            CircuitState::Open { probe_at } => probe_at <= now,
+            CircuitState::HalfOpen { probed_at } => probed_at + probe_interval <= now,
+        };
+        if probe {
+            circuit.state = CircuitState::HalfOpen { probed_at: now };
+        }
+        probe
+    }
+
+    /// Whether source failed enough to be considered down
+    pub fn is_down(&self, source: &str) -> bool {
+        self.circuits.get(source).map_or(false, |c| c.state != CircuitState::Closed)
+    }
+
+    /// Record that request to the source succeeded, queues notice if it recovered
+    pub fn succeeded(&mut self, source: &str) {
+        let circuit = match self.circuits.remove(source) {
+            None => return,
+            Some(circuit) => circuit,
+        };
+        if let Some(since) = circuit.down_since {
+            self.notices.push(format!("{} is back up, it was down since {}", source, since.format(DATE_FORMAT)));
+        }
+    }
+
+    /// Record that request to the source failed, queues notice if it went down
+    pub fn failed(&mut self, source: &str, error: &str, now: NaiveDateTime) {
+        let circuit = self.circuits.entry(source.to_owned()).or_insert(Circuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            last_error: None,
+            down_since: None,
+        });
+        circuit.failures += 1;
+        circuit.last_error = Some(error.to_owned());
+
+        let probe_at = now + self.probe_interval;
+        match circuit.state {
+            CircuitState::Closed if circuit.failures >= self.threshold => {
+                circuit.state = CircuitState::Open { probe_at: probe_at };
+                circuit.down_since = Some(now);
+                self.notices.push(format!("{} is down after {} failures in a row, last: {}",
+                                          source, circuit.failures, error));
+            }
+            CircuitState::Closed => {}
+            _ => {
+                // probe failed, still down
+                circuit.state = CircuitState::Open { probe_at: probe_at };
+            }
+        }
+    }
+
+    /// Notices about sources that went down or recovered since the last call
+    pub fn take_notices(&mut self) -> Vec<String> {
+        mem::replace(&mut self.notices, vec![])
+    }
+
+    /// One line for each source that fails, for status reports
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self.circuits.iter()
+            .map(|(source, circuit)| {
+                let state = match (circuit.state, circuit.down_since) {
+                    (CircuitState::Open { probe_at }, Some(since)) => {
+                        format!("down since {}, next probe at {}",
+                                since.format(DATE_FORMAT), probe_at.format(DATE_FORMAT))
+                    }
+                    (CircuitState::HalfOpen { .. }, _) => "down, probing now".to_owned(),
+                    _ => "failing".to_owned(),
+                };
+                format!("{}: {}, {} errors in a row, last: {}",
+                        source, state, circuit.failures, circuit.last_error.as_ref().map_or("", |e| e.as_str()))
+            })
+            .collect();
+        lines.sort();
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "www.linux.org.ru";
+
+    fn at(hour: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2017, 11, 25).and_hms(hour, min, 0)
+    }
+
+    #[test]
+    fn circuit_opens_after_threshold() {
+        let mut health = Health::new(3, Duration::minutes(5));
+        health.failed(HOST, "timed out", at(12, 0));
+        health.failed(HOST, "timed out", at(12, 1));
+        assert!(!health.is_down(HOST));
+        assert!(health.allows(HOST, at(12, 1)));
+        assert!(health.take_notices().is_empty());
+
+        health.failed(HOST, "timed out", at(12, 2));
+        assert!(health.is_down(HOST));
+        assert!(health.is_blocked(HOST, at(12, 3)));
+        assert!(!health.allows(HOST, at(12, 3)));
+        assert_eq!(health.take_notices().len(), 1);
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let mut health = Health::new(2, Duration::minutes(5));
+        health.failed(HOST, "timed out", at(12, 0));
+        health.succeeded(HOST);
+        health.failed(HOST, "timed out", at(12, 1));
+        assert!(!health.is_down(HOST));
+        assert!(health.take_notices().is_empty());
+    }
+
+    #[test]
+    fn one_probe_is_let_through() {
+        let mut health = Health::new(1, Duration::minutes(5));
+        health.failed(HOST, "timed out", at(12, 0));
+        assert!(health.is_blocked(HOST, at(12, 4)));
+        assert!(!health.is_blocked(HOST, at(12, 5)));
+
+        // half open: the first request probes, the rest wait for it
+        assert!(health.allows(HOST, at(12, 5)));
+        assert!(!health.allows(HOST, at(12, 5)));
+        assert!(health.is_blocked(HOST, at(12, 6)));
+
+        // failed probe opens the circuit again
+        health.failed(HOST, "timed out", at(12, 6));
+        assert!(!health.allows(HOST, at(12, 7)));
+        assert!(health.allows(HOST, at(12, 11)));
+
+        // successful probe closes it
+        health.take_notices();
+        health.succeeded(HOST);
+        assert!(!health.is_down(HOST));
+        assert!(health.allows(HOST, at(12, 11)));
+        assert_eq!(health.take_notices(), vec![format!("{} is back up, it was down since {}",
+                                                       HOST, at(12, 0).format(DATE_FORMAT))]);
+    }
+
+    #[test]
+    fn lost_probe_is_replaced() {
+        let mut health = Health::new(1, Duration::minutes(5));
+        health.failed(HOST, "timed out", at(12, 0));
+        assert!(health.allows(HOST, at(12, 5)));
+
+        // probe never reported back
+        assert!(!health.allows(HOST, at(12, 9)));
+        assert!(health.allows(HOST, at(12, 10)));
+    }
+
+    #[test]
+    fn hosts_are_separate() {
+        let mut health = Health::new(1, Duration::minutes(5));
+        health.failed(HOST, "timed out", at(12, 0));
+        assert!(health.is_down(HOST));
+        assert!(!health.is_down("matrix.org"));
+        assert!(health.allows("matrix.org", at(12, 1)));
+    }
+}
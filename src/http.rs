@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::result;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::prelude::*;
+use config::Config;
+use reqwest;
+use reqwest::{Body, Certificate, Client, Proxy, Response, StatusCode, Url};
+use reqwest::header::{Headers, UserAgent};
+use reqwest::unstable::async;
+use tokio_core::reactor::Handle;
+
+use entities::*;
+use health::Health;
+
+/// Identifies the bot to sites if `http.user_agent` is not configured
+const DEFAULT_USER_AGENT: &str = concat!("lor-bot/", env!("CARGO_PKG_VERSION"));
+
+/// Request timeout if `http.timeout_secs` is not configured
+const DEFAULT_TIMEOUT_SECS: i64 = 30;
+
+/// Settings of connections to hosts matching the pattern, from `http.hosts` config property
+#[derive(Debug, Clone)]
+struct HostRule {
+    /// host name, `*.example.com` also matches subdomains, `*` matches any host
+    pattern: String,
+    /// proxy URL, e.g. `http://127.0.0.1:8118`
+    proxy: Option<String>,
+    /// don't verify that certificate matches the host name
+    insecure: bool,
+}
+
+impl HostRule {
+
+    fn matches(&self, host: &str) -> bool {
+        if self.pattern == "*" {
+            return true;
+        }
+        if self.pattern.starts_with("*.") {
+            let domain = &self.pattern[2..];
+            return host == domain || host.ends_with(&self.pattern[1..]);
+        }
+        host == self.pattern
+    }
+}
+
+/// HTTP client settings from `http` section of config, every module builds its clients from these
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    user_agent: String,
+    /// requests that take longer than this fail
+    pub timeout: Duration,
+    /// extra root certificates to trust, DER-encoded
+    root_certificates: Vec<Vec<u8>>,
+    /// first matching rule applies to the host
+    hosts: Vec<HostRule>,
+}
+
+impl HttpConfig {
+
+    /// Read `http` section of the config, everything in it is optional
+    pub fn load(cfg: &Config) -> Result<HttpConfig> {
+        // polling section had its own timeout before there was http section, it's still read if that's all
+        // there is, so upgrades don't lose it
+        let timeout = match (cfg.get_int("http.timeout_secs").ok(), cfg.get_int("polling.timeout_secs").ok()) {
+            (Some(timeout), Some(_)) => {
+                warn!("polling.timeout_secs is ignored, http.timeout_secs is used instead");
+                timeout
+            }
+            (None, Some(timeout)) => {
+                warn!("polling.timeout_secs is deprecated, move it to http.timeout_secs");
+                timeout
+            }
+            (timeout, None) => timeout.unwrap_or(DEFAULT_TIMEOUT_SECS),
+        }.max(1) as u64;
+
+        let mut root_certificates = vec![];
+        for path in cfg.get_array("http.root_certificates").unwrap_or_default() {
+            let path = path.into_str().map_err(|error| CoreError::CustomError(error.to_string()))?;
+            let mut der = vec![];
+            fs::File::open(&path)?.read_to_end(&mut der)?;
+            root_certificates.push(der);
+        }
+
+        let mut hosts = vec![];
+        for rule in cfg.get_array("http.hosts").unwrap_or_default() {
+            let mut rule = rule.into_table().map_err(|error| CoreError::CustomError(error.to_string()))?;
+            let pattern = match rule.remove("host").map(|host| host.into_str()) {
+                Some(Ok(pattern)) => pattern,
+                _ => return Err(CoreError::CustomError("Every rule in http.hosts must have a host".to_owned())),
+            };
+            let proxy = rule.remove("proxy").and_then(|proxy| proxy.into_str().ok());
+            if let Some(ref proxy) = proxy {
+                if proxy.starts_with("socks") {
+                    // Tor can expose HTTP tunnel for this, see HTTPTunnelPort in its manual
+                    let reason = format!("SOCKS proxy for {} is not supported, use HTTP proxy instead", pattern);
+                    return Err(CoreError::CustomError(reason));
+                }
+            }
+            let insecure = rule.remove("insecure").and_then(|insecure| insecure.into_bool().ok()).unwrap_or(false);
+            hosts.push(HostRule { pattern: pattern, proxy: proxy, insecure: insecure });
+        }
+
+        Ok(HttpConfig {
+            user_agent: cfg.get_str("http.user_agent").unwrap_or_else(|_| DEFAULT_USER_AGENT.to_owned()),
+            timeout: Duration::from_secs(timeout),
+            root_certificates: root_certificates,
+            hosts: hosts,
+        })
+    }
+
+    fn rule_for(&self, host: &str) -> Option<&HostRule> {
+        self.hosts.iter().find(|rule| rule.matches(host))
+    }
+
+    fn headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        headers.set(UserAgent::new(self.user_agent.to_owned()));
+        headers
+    }
+
+    /// Blocking client for requests to the host, used by upstreams
+    pub fn client_for(&self, host: &str) -> Result<Client> {
+        let mut builder = Client::builder()?;
+        builder.default_headers(self.headers()).timeout(self.timeout);
+        for der in &self.root_certificates {
+            builder.add_root_certificate(Certificate::from_der(der)?);
+        }
+        if let Some(rule) = self.rule_for(host) {
+            if let Some(ref proxy) = rule.proxy {
+                builder.proxy(Proxy::all(proxy.as_str())?);
+            }
+            if rule.insecure {
+                builder.danger_disable_hostname_verification();
+            }
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Non-blocking client for requests to the host on the reactor, used by adapters.
+    /// Reactor client has no timeouts of its own, its users set deadlines with `timeout`
+    pub fn async_client_for(&self, host: &str, handle: &Handle) -> Result<async::Client> {
+        let mut builder = async::Client::builder()?;
+        builder.default_headers(self.headers());
+        for der in &self.root_certificates {
+            builder.add_root_certificate(Certificate::from_der(der)?);
+        }
+        if let Some(rule) = self.rule_for(host) {
+            if let Some(ref proxy) = rule.proxy {
+                builder.proxy(Proxy::all(proxy.as_str())?);
+            }
+            if rule.insecure {
+                builder.danger_disable_hostname_verification();
+            }
+        }
+        Ok(builder.build(handle)?)
+    }
+}
+
+/// Host of the URL, clients are configured per host
+pub fn host_of(url: &str) -> Result<String> {
+    let parsed = Url::parse(url).map_err(|error| CoreError::CustomError(format!("Invalid URL {}: {}", url, error)))?;
+    Ok(parsed.host_str().unwrap_or_default().to_owned())
+}
+
+/// Fail right away if the host is down, unless it's time to probe it
+pub fn check_health(health: &Mutex<Health>, host: &str) -> Result<()> {
+    if health.lock().expect("Health must not be poisoned!").allows(host, Utc::now().naive_utc()) {
+        return Ok(());
+    }
+    Err(CoreError::CustomError(format!("{} is down", host)))
+}
+
+/// Record how request to the host went. Only transport errors and server errors mean the host is down,
+/// any other answer, even 404, shows it's up
+pub fn record_health(health: &Mutex<Health>, host: &str, answer: result::Result<StatusCode, String>) {
+    let mut health = health.lock().expect("Health must not be poisoned!");
+    match answer {
+        Ok(status) if status.is_server_error() => {
+            health.failed(host, &format!("answered with {}", status), Utc::now().naive_utc())
+        }
+        Ok(_) => health.succeeded(host),
+        Err(error) => health.failed(host, &error, Utc::now().naive_utc()),
+    }
+}
+
+/// Blocking HTTP client of upstreams. Requests go through clients built for their hosts,
+/// see `HttpConfig::client_for`, so every host gets its own rule.
+/// Requests to hosts that are down fail without being sent, see `Health`
+pub struct HttpClient {
+    config: HttpConfig,
+    health: Arc<Mutex<Health>>,
+    /// clients by hosts they're built for
+    clients: Mutex<HashMap<String, Client>>,
+}
+
+impl HttpClient {
+
+    pub fn new(config: HttpConfig, health: Arc<Mutex<Health>>) -> HttpClient {
+        HttpClient {
+            config: config,
+            health: health,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Client configured for the host, clients are built once per host
+    fn client_for(&self, host: &str) -> Result<Client> {
+        let mut clients = self.clients.lock().expect("HTTP clients must not be poisoned!");
+        if let Some(client) = clients.get(host) {
+            return Ok(client.clone());
+        }
+
+        let client = self.config.client_for(host)?;
+        clients.insert(host.to_owned(), client.clone());
+        Ok(client)
+    }
+
+    /// Send request to the URL with client of its host and record how it went
+    fn send<F>(&self, url: &str, send: F) -> Result<Response>
+        where F: FnOnce(&Client) -> result::Result<Response, reqwest::Error> {
+        let host = host_of(url)?;
+        check_health(&self.health, &host)?;
+        let response = send(&self.client_for(&host)?);
+        let answer = match response {
+            Ok(ref response) => Ok(response.status()),
+            Err(ref error) => Err(error.to_string()),
+        };
+        record_health(&self.health, &host, answer);
+        Ok(response?)
+    }
+
+    pub fn get(&self, url: &str) -> Result<Response> {
+        self.send(url, |client| client.get(url)?.send())
+    }
+
+    pub fn post<B: Into<Body>>(&self, url: &str, body: B) -> Result<Response> {
+        self.send(url, |client| client.post(url)?.body(body).send())
+    }
+
+    pub fn put<B: Into<Body>>(&self, url: &str, body: B) -> Result<Response> {
+        self.send(url, |client| client.put(url)?.body(body).send())
+    }
+}
@@ -22,8 +22,13 @@ extern crate derive_new;
 #[macro_use]
 extern crate derive_error;
 extern crate crossbeam;
+extern crate futures;
+extern crate tokio_core;
+extern crate ctrlc;
 extern crate chrono;
+extern crate chrono_tz;
 extern crate uuid;
+extern crate regex;
 //#[macro_use]
 //extern crate lazy_static;
 
@@ -31,24 +36,48 @@ use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
 use database::schema::user_info;
 
-use reqwest::Client;
+
+use futures::{Future, Stream};
+use futures::sync::mpsc::{self as async_mpsc, UnboundedReceiver, UnboundedSender};
+use tokio_core::reactor::Core;
 
 use config::Config;
 use config::File;
 
+use chrono::prelude::*;
+
+use std::result;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::path::Path;
 use std::fs::create_dir;
 use std::collections::HashMap;
+use std::collections::HashSet;
 
 pub mod database;
 mod entities;
+mod commands;
+mod settings;
+mod templates;
+mod digest;
+mod filters;
+mod scheduler;
+mod http;
+mod health;
 mod modules;
 
 use entities::*;
 use entities::UpstreamUpdate::*;
 use modules::matrix_org::Matrix;
+use modules::http_cache::HttpCache;
+use settings::RoomSettings;
+use filters::Filters;
+use scheduler::{Account, Activity, Scheduler};
+use http::{HttpClient, HttpConfig};
+use health::Health;
 
 /*
 lazy_static! {
@@ -63,13 +92,68 @@ lazy_static! {
 }
 */
 
+/// How often accounts are scheduled and upstreams are synced
+const CYCLE_MILLIS: u64 = 3000;
+
+/// How many accounts are polled at the same time if `polling.concurrency` is not configured
+const DEFAULT_POLL_CONCURRENCY: i64 = 4;
+
+/// Failures in a row after which source is considered down if `health.failure_threshold` is not configured
+const DEFAULT_FAILURE_THRESHOLD: i64 = 5;
+
+/// How often sources that are down are probed if `health.probe_interval_secs` is not configured
+const DEFAULT_PROBE_INTERVAL_SECS: i64 = 300;
+
+/// Set when bot is asked to stop, threads finish what they're doing and exit
+static SHUTDOWN: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Upstreams by their types, shared between sync thread and dispatcher
+type Upstreams = HashMap<String, Box<Upstream>>;
+
+/// What polling downstream account found or why polling failed
+type PollResult = result::Result<Polled, String>;
+
+/// Account polling thread is asked to poll, whether its replies are polled too
+/// and whether its page is parsed even if it didn't change
+type PollJob = (Account, bool, bool);
+
+/// What sync and polling threads send to dispatcher
+enum Event {
+    /// Commands users issued in upstream of this type and where its sync stopped after them
+    Commands(String, Vec<UpstreamCommand>, String),
+    /// Downstream account was polled
+    Polled(Account, PollResult),
+}
+
 #[derive(new)]
 struct GlobalData {
     conn: SqliteConnection,
     config: Config,
-    http_client: Client,
-    connects: HashMap<String, Box<Upstream>>,
+    /// settings of HTTP clients, from `http` config section
+    http_config: HttpConfig,
+    /// client for requests to upstreams, shared with sync thread
+    http_client: Arc<HttpClient>,
     requests: Vec<UserInfo>,
+    /// users that can issue admin commands, from `admin.operators` config property
+    operators: HashSet<String>,
+    /// filters of links by link ids, links without filters are absent
+    filters: HashMap<i32, Filters>,
+    /// decides which downstream accounts are polled when
+    scheduler: Scheduler,
+    /// which hosts are down, shared with HTTP clients of all threads
+    health: Arc<Mutex<Health>>,
+    /// upstream type and chat where operators are notified about sources going down and recovering,
+    /// from `health.notify_chat` config property
+    notify_chat: Option<(String, String)>,
+    /// polling status of requests by their keys
+    #[new(default)]
+    statuses: HashMap<String, LinkStatus>,
+    /// adapters that operators paused polling for
+    #[new(default)]
+    paused: HashSet<Adapter>,
+    /// when messages were pushed to chats during the last minute, for rate limits
+    #[new(default)]
+    sent: HashMap<(String, String), Vec<NaiveDateTime>>,
 }
 
 fn main() {
@@ -78,6 +162,12 @@ fn main() {
         log4rs::init_file("conf/log4rs.yml", Default::default()).expect("Must be able to initialize logging!");
     }
 
+    // stop gracefully under systemd and on Ctrl+C
+    ctrlc::set_handler(|| {
+        info!("Got termination signal, shutting down after this cycle");
+        SHUTDOWN.store(true, Ordering::SeqCst);
+    }).expect("Must be able to set signal handler!");
+
     // init database
     if !Path::new("data").exists() {
         debug!("Creating data dir for configs in cwd");
@@ -85,20 +175,48 @@ fn main() {
     }
     let conn = SqliteConnection::establish("data/acc-linker-bot.db").expect("Error connecting to sqlite3 db!");
 
-    // init HTTP client
-    let client = Client::new().expect("Must be able to initialize http client!");
-
     // init bot configuration
     let mut cfg = Config::new();
     cfg.merge(File::with_name("conf/bot-config.yml")).expect("Must be able to parse config in conf/bot-config.yml");
 
+    // operators may manage the bot from any chat
+    let operators: HashSet<String> = cfg.get_array("admin.operators")
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|op| op.into_str().ok())
+        .collect();
+
     // retrieve list of bindings from database
     let user_infos: Vec<UserInfo> = user_info::table.load(&conn).unwrap();
     info!("Updates: {:?}", user_infos);
-    let mut app_data = GlobalData::new(conn, cfg, client, HashMap::new(), user_infos);
-    app_data.connects.insert("Matrix".to_owned(), Box::new(Matrix::default()));
+    let filters = database::load_filters(&conn).expect("Error loading link filters!");
+    let mut scheduler = Scheduler::new(database::load_schedules(&conn).expect("Error loading polling schedule!"));
+    scheduler.forget_unlinked(&conn, &user_infos);
+    // hosts are left alone for a while if they keep failing
+    let threshold = cfg.get_int("health.failure_threshold").unwrap_or(DEFAULT_FAILURE_THRESHOLD).max(1) as u32;
+    let probe_interval = cfg.get_int("health.probe_interval_secs").unwrap_or(DEFAULT_PROBE_INTERVAL_SECS);
+    let health = Arc::new(Mutex::new(Health::new(threshold, chrono::Duration::seconds(probe_interval))));
+    let notify_chat = cfg.get_str("health.notify_chat").ok()
+        .and_then(|chat| {
+            let mut parts = chat.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(upstream_type), Some(chat_id)) => Some((upstream_type.to_owned(), chat_id.to_owned())),
+                _ => None,
+            }
+        });
 
-    start_event_loop(app_data);
+    // init HTTP client
+    let http_config = HttpConfig::load(&cfg).expect("Must be able to parse http section of config!");
+    let client = Arc::new(HttpClient::new(http_config.clone(), health.clone()));
+
+    let app_data = GlobalData::new(conn, cfg, http_config, client, user_infos, operators, filters, scheduler,
+                                   health, notify_chat);
+    let mut connects: Upstreams = HashMap::new();
+    let sync_token = database::load_sync_token(&app_data.conn, "Matrix").expect("Error loading Matrix sync state!");
+    let thread_roots = database::load_thread_roots(&app_data.conn, "Matrix").expect("Error loading Matrix threads!");
+    connects.insert("Matrix".to_owned(), Box::new(Matrix::new(&app_data.config, sync_token, thread_roots)));
+
+    start_event_loop(app_data, connects);
 }
 
 /// Main bot event loop.
@@ -110,13 +228,225 @@ fn main() {
 /// Polls data from downstreams that users verified. If any updates found, report them
 /// to the corresponding upstream.
 ///
+/// Upstreams are synced on their own thread and downstreams are polled concurrently on a reactor of
+/// polling thread, so one slow source doesn't stall the others. Both send what they got to this thread,
+/// which is the only one that works with the database.
+///
+/// On SIGINT or SIGTERM the loop finishes current cycle, saves state and logs out of upstreams.
+///
 /// Keep CPU overhead low so it can be run on RPi or ARM VPS.
-fn start_event_loop(mut data: GlobalData) {
-    let client = &data.http_client;
+fn start_event_loop(mut data: GlobalData, connects: Upstreams) {
+    let concurrency = data.config.get_int("polling.concurrency").unwrap_or(DEFAULT_POLL_CONCURRENCY).max(1) as usize;
+    let poll_config = data.http_config.clone();
+    let sync_client = data.http_client.clone();
+    let poll_health = data.health.clone();
+    let sync_config = data.config.clone();
+
+    let (job_sender, jobs) = async_mpsc::unbounded::<PollJob>();
+    let (event_sender, events) = mpsc::channel::<Event>();
+
+    crossbeam::scope(|scope| {
+        let poll_events = event_sender.clone();
+        scope.spawn(move || poll_downstreams(jobs, poll_events, concurrency, poll_config, poll_health));
+
+        let connects = &connects;
+        let (sync_client, sync_config) = (&*sync_client, &sync_config);
+        scope.spawn(move || sync_upstreams(sync_client, sync_config, connects, event_sender));
+
+        dispatch(&mut data, connects, job_sender, events);
+    });
+
+    // sync thread is gone, nothing else uses upstreams now
+    for (upstream_type, upstream) in &connects {
+        info!("Disconnecting from {}", upstream_type);
+        upstream.disconnect(&data.http_client);
+    }
+    info!("Bye");
+}
+
+/// Dispatcher part of the event loop, see `start_event_loop`.
+///
+/// Each cycle hands accounts that are due to polling thread, then handles commands and poll results
+/// as they arrive.
+fn dispatch(data: &mut GlobalData, connects: &Upstreams, jobs: UnboundedSender<PollJob>,
+            events: Receiver<Event>) {
+    // accounts handed to polling thread, they're not scheduled again until their results are back
+    let mut in_flight: HashSet<Account> = HashSet::new();
     loop {
-        // connect all upstreams and process invites/leaves etc.
-        for upstream in data.connects.values_mut() {
-            upstream.connect(client, &data.config);
+        // settings may change with commands, so they're only cached for one cycle
+        let mut chat_settings: HashMap<(String, String), RoomSettings> = HashMap::new();
+
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            shut_down(data, connects, in_flight, &events, &mut chat_settings);
+            return;
+        }
+
+        // poll every downstream account that is due once, however many links it has
+        let now = Utc::now().naive_utc();
+        let mut accounts: HashMap<Account, bool> = HashMap::new();
+        let mut pending: HashSet<Account> = HashSet::new();
+        for user_info in &data.requests {
+            if data.paused.contains(&user_info.adapter) {
+                continue;
+            }
+
+            let settings = load_settings(&data.conn, &mut chat_settings, &user_info.upstream_type, &user_info.chat_id);
+            if !settings.adapter_allowed(user_info.adapter) {
+                continue;
+            }
+
+            let account = (user_info.adapter, user_info.linked_user_id.to_owned());
+            if in_flight.contains(&account) {
+                continue;
+            }
+
+            // hosts that are down are only probed once in a while
+            if data.health.lock().expect("Health must not be poisoned!").is_blocked(account.0.host(), now) {
+                continue;
+            }
+
+            // links waiting for verification look for proof in whatever account has, changed or not
+            if !user_info.verified {
+                pending.insert(account.clone());
+            }
+
+            // replies are only requested for verified links, nobody else can verify it for user
+            let with_replies = accounts.entry(account).or_insert(false);
+            *with_replies = *with_replies || (user_info.verified && user_info.track_replies);
+        }
+
+        let due = data.scheduler.pick_due(accounts, now);
+        let mut probing: HashSet<Adapter> = HashSet::new();
+        for (account, with_replies) in due {
+            let down = data.health.lock().expect("Health must not be poisoned!").is_down(account.0.host());
+            if down && !probing.insert(account.0) {
+                // another account of this adapter is probing its host
+                continue;
+            }
+            in_flight.insert(account.clone());
+            let always_parse = pending.contains(&account);
+            jobs.unbounded_send((account, with_replies, always_parse)).expect("Polling thread must be running!");
+        }
+
+        // handle whatever comes in until the next cycle
+        let cycle_end = Instant::now() + Duration::from_millis(CYCLE_MILLIS);
+        loop {
+            let now = Instant::now();
+            if now >= cycle_end {
+                break;
+            }
+
+            let event = events.recv_timeout(cycle_end - now);
+            match event {
+                Ok(event) => handle_event(data, connects, &mut in_flight, event, &mut chat_settings),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    error!("All polling and sync threads are gone, stopping");
+                    return;
+                }
+            }
+        }
+
+        // requests of all threads could take hosts down or bring them back meanwhile
+        let notices = data.health.lock().expect("Health must not be poisoned!").take_notices();
+        for notice in notices {
+            notify_operators(data, connects, &notice);
+        }
+
+        send_digests(&data.conn, connects, &data.http_client, &data.requests, &mut data.sent, &mut chat_settings);
+        save_thread_roots(&data.conn, connects);
+        debug!("Done dispatching, next cycle...");
+    }
+}
+
+/// Handle what sync or polling thread sent
+fn handle_event(data: &mut GlobalData, connects: &Upstreams, in_flight: &mut HashSet<Account>, event: Event,
+                chat_settings: &mut HashMap<(String, String), RoomSettings>) {
+    match event {
+        Event::Commands(upstream_type, demands, sync_token) => {
+            process_commands(data, connects, &upstream_type, demands, chat_settings);
+            // commands are handled, so restart continues after them
+            if let Err(error) = database::save_sync_token(&data.conn, &upstream_type, &sync_token) {
+                error!("Couldn't save where sync of {} stopped: {:?}", upstream_type, error);
+            }
+        }
+        Event::Polled(account, result) => {
+            in_flight.remove(&account);
+            process_polled(data, connects, &account, &result, chat_settings);
+        }
+    }
+}
+
+/// Finish the work in progress and save everything that is only kept in memory.
+///
+/// Results of polls in progress are waited for, they time out on their own anyway. Held back updates
+/// that are due are sent, the rest stay in DB. Links save their progress, pending ones are saved too,
+/// so users can still verify them after restart.
+fn shut_down(data: &mut GlobalData, connects: &Upstreams, mut in_flight: HashSet<Account>, events: &Receiver<Event>,
+             chat_settings: &mut HashMap<(String, String), RoomSettings>) {
+    info!("Waiting for {} polls in progress", in_flight.len());
+    let deadline = Instant::now() + data.http_config.timeout + Duration::from_millis(CYCLE_MILLIS);
+    while !in_flight.is_empty() {
+        let now = Instant::now();
+        if now >= deadline {
+            warn!("Polls of {} accounts didn't finish in time", in_flight.len());
+            break;
+        }
+
+        let event = events.recv_timeout(deadline - now);
+        match event {
+            Ok(event) => handle_event(data, connects, &mut in_flight, event, chat_settings),
+            Err(_) => break,
+        }
+    }
+
+    send_digests(&data.conn, connects, &data.http_client, &data.requests, &mut data.sent, chat_settings);
+    save_thread_roots(&data.conn, connects);
+
+    let mut saved = 0;
+    for link in &mut data.requests {
+        match database::save_link(&data.conn, link) {
+            Ok(id) => {
+                link.id = id;
+                saved += 1;
+            }
+            Err(error) => error!("Couldn't save link to {}: {:?}", link.linked_user_id, error),
+        }
+    }
+    info!("Saved {} links", saved);
+}
+
+/// Polling thread, polls accounts it's given on its own reactor, at most `concurrency` at a time,
+/// and sends results back. Requests that take longer than configured HTTP timeout fail, see `HttpCache`
+fn poll_downstreams(jobs: UnboundedReceiver<PollJob>, events: Sender<Event>, concurrency: usize,
+                    http_config: HttpConfig, health: Arc<Mutex<Health>>) {
+    let mut core = Core::new().expect("Must be able to start polling reactor!");
+    let cache = HttpCache::new(core.handle(), http_config, health);
+
+    let polls = jobs
+        .map(|((adapter, linked_user_id), with_replies, always_parse)| {
+            adapter.poll(&cache, vec![linked_user_id.to_owned()], with_replies, always_parse)
+                .map_err(|error| error.to_string())
+                .then(move |result| Ok::<_, ()>(((adapter, linked_user_id), result)))
+        })
+        .buffer_unordered(concurrency)
+        .for_each(|(account, result)| events.send(Event::Polled(account, result)).map_err(|_| ()));
+
+    // jobs end when dispatcher is gone
+    let _ = core.run(polls);
+}
+
+/// Upstream sync loop, connects all upstreams and sends commands users issued there to dispatcher,
+/// along with positions in event streams, so dispatcher saves them once commands are handled
+fn sync_upstreams(client: &HttpClient, cfg: &Config, connects: &Upstreams, events: Sender<Event>) {
+    // positions dispatcher was told about, by upstream types
+    let mut sent_tokens: HashMap<&str, String> = connects.iter()
+        .map(|(upstream_type, upstream)| (upstream_type.as_str(), upstream.sync_token()))
+        .collect();
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        for (upstream_type, upstream) in connects {
+            // process invites/leaves etc., hosts that are down fail right away until it's time to probe them
+            upstream.connect(client, cfg);
             let new_demands = upstream.check_updates(client);
             let demands = match new_demands {
                 Err(error) => {
@@ -126,67 +456,977 @@ fn start_event_loop(mut data: GlobalData) {
                 Ok(demands) => demands,
             };
 
-            // We got commands from upstream, process them
-            for d in demands {
-                match d {
-                    Link(request) => {
-                        if data.requests.contains(&request) {
-                            // this request was already present, report it
-                            upstream.report_duplicate_link(client, request);
-                            continue;
+            let sync_token = upstream.sync_token();
+            if demands.is_empty() && sent_tokens.get(upstream_type.as_str()) == Some(&sync_token) {
+                continue;
+            }
+            sent_tokens.insert(upstream_type.as_str(), sync_token.to_owned());
+            if events.send(Event::Commands(upstream_type.to_owned(), demands, sync_token)).is_err() {
+                return; // dispatcher is gone
+            }
+        }
+
+        thread::sleep(Duration::from_millis(CYCLE_MILLIS));
+    }
+}
+
+/// Process commands users issued in the upstream: check that they're allowed and execute them
+fn process_commands(data: &mut GlobalData, connects: &Upstreams, upstream_type: &str,
+                    demands: Vec<UpstreamCommand>, chat_settings: &mut HashMap<(String, String), RoomSettings>) {
+    let client = &data.http_client;
+    let upstream = match connects.get(upstream_type) {
+        None => return,
+        Some(upstream) => upstream,
+    };
+
+    // We got commands from upstream, process them
+    for d in demands {
+        let origin = d.origin;
+        let is_operator = data.operators.contains(&origin.user_id);
+
+        // banned users are ignored altogether
+        match database::is_banned(&data.conn, upstream_type, &origin.user_id) {
+            Ok(false) => {}
+            Ok(true) if is_operator => {}
+            Ok(true) => {
+                info!("Ignoring command from banned user {}", origin.user_id);
+                continue;
+            }
+            Err(error) => {
+                // better to ignore a command than to let banned user in
+                error!("Couldn't check whether {} is banned: {:?}", origin.user_id, error);
+                continue;
+            }
+        }
+
+        if let Admin(_) = d.update {
+            if !is_operator {
+                upstream.reply(client, &origin, "Sorry, only bot operators can do that".to_owned());
+                continue;
+            }
+        }
+
+        // check that user is allowed to do this in the chat
+        if let Some(permission) = commands::required_permission(&d.update, &origin) {
+            let required = match database::load_permissions(&data.conn, upstream_type, &origin.chat_id) {
+                Ok(perms) => perms.level_for(permission),
+                Err(error) => {
+                    error!("Couldn't load permissions for {}: {:?}", origin.chat_id, error);
+                    continue;
+                }
+            };
+            let actual = upstream.power_level(client, &origin.chat_id, &origin.user_id).unwrap_or_else(|error| {
+                error!("Couldn't get power level of {}: {:?}", origin.user_id, error);
+                0
+            });
+            if actual < required {
+                let refusal = format!("Sorry, you need power level {} in this chat to do that, you have {}",
+                                      required, actual);
+                upstream.reply(client, &origin, refusal);
+                continue;
+            }
+        }
+
+        match d.update {
+            Link(request) => {
+                let settings = load_settings(&data.conn, chat_settings, upstream_type, &origin.chat_id);
+                if !settings.adapter_allowed(request.adapter) {
+                    let refusal = format!("Sorry, links to {} are not allowed in this chat",
+                                          request.adapter.to_string());
+                    upstream.reply(client, &origin, refusal);
+                    continue;
+                }
+                let existing = data.requests.iter().position(|i| *i == request);
+                if let Some(idx) = existing {
+                    if data.requests[idx].track_replies == request.track_replies {
+                        // this request was already present, report it
+                        upstream.report_duplicate_link(client, &origin, request);
+                        continue;
+                    }
+
+                    // same link asked with or without replies, that's a change of the existing one
+                    let answer = track_replies(&data.conn, &mut data.requests[idx], request.track_replies);
+                    upstream.reply(client, &origin, answer);
+                    continue;
+                }
+
+                // pending links are saved right away, so they have ids operators can refer to
+                let mut request = request;
+                request.id = match database::save_link(&data.conn, &request) {
+                    Ok(id) => id,
+                    Err(error) => {
+                        error!("Couldn't save link to {}: {:?}", request.linked_user_id, error);
+                        upstream.reply(client, &origin, format!("Couldn't link {}", request.linked_user_id));
+                        continue;
+                    }
+                };
+                upstream.report_link_to_verify(client, &origin, &request);
+                // account may be linked elsewhere already and have a long interval, proof shouldn't wait for it
+                let account = (request.adapter, request.linked_user_id.to_owned());
+                data.scheduler.poll_soon(&data.conn, &account, Utc::now().naive_utc());
+                data.requests.push(request);
+            }
+            Unlink(user_info) => {
+                let answer = unlink(&data.conn, &mut data.requests, &user_info);
+                data.scheduler.forget_unlinked(&data.conn, &data.requests);
+                upstream.reply(client, &origin, answer)
+            }
+            UnlinkAll { user_name, upstream_type, chat_id, confirmed } => {
+                let answer = unlink_all(&data.conn, &mut data.requests, &upstream_type, &user_name,
+                                        chat_id.as_ref().map(|c| c.as_str()), confirmed,
+                                        upstream.command_prefix());
+                data.scheduler.forget_unlinked(&data.conn, &data.requests);
+                upstream.reply(client, &origin, answer)
+            }
+            Explain { command, .. } => upstream.explain_command(client, &origin, &command),
+            Help { command } => {
+                let help = commands::help(upstream.command_prefix(), command.as_ref().map(|c| c.as_str()));
+                upstream.reply(client, &origin, help)
+            }
+            List => {
+                let links = commands::list_links(&data.requests, &data.filters, upstream_type, &origin);
+                upstream.reply(client, &origin, links)
+            }
+            Status => {
+                let problems = data.health.lock().expect("Health must not be poisoned!").describe();
+                let status = commands::link_status(&data.requests, &data.statuses, &problems, upstream_type, &origin);
+                upstream.reply(client, &origin, status)
+            }
+            Permissions { change } => {
+                let perms = database::load_permissions(&data.conn, upstream_type, &origin.chat_id)
+                    .and_then(|mut perms| {
+                        if let Some((permission, level)) = change {
+                            perms.set_level(permission, level);
+                            database::save_permissions(&data.conn, &perms)?;
                         }
-                        upstream.report_link_to_verify(client, &request);
-                        data.requests.push(request);
+                        Ok(perms)
+                    });
+                match perms {
+                    Ok(perms) => upstream.reply(client, &origin, commands::describe_permissions(&perms)),
+                    Err(error) => error!("Couldn't change permissions for {}: {:?}", origin.chat_id, error),
+                }
+            }
+            Filter { link, change } => {
+                let answer = change_filters(&data.conn, &data.requests, &mut data.filters, &link, change);
+                upstream.reply(client, &origin, answer)
+            }
+            Mute { link, duration } => {
+                let until = duration
+                    .and_then(|d| Utc::now().naive_utc().checked_add_signed(d))
+                    .unwrap_or_else(muted_forever);
+                let answer = mute(&data.conn, &mut data.requests, &link, Some(until));
+                upstream.reply(client, &origin, answer)
+            }
+            Unmute(link) => {
+                let answer = mute(&data.conn, &mut data.requests, &link, None);
+                upstream.reply(client, &origin, answer)
+            }
+            Settings { name, value } => {
+                let answer = change_settings(&data.conn, upstream_type, &origin.chat_id,
+                                             name.as_ref().map(|n| n.as_str()),
+                                             value.as_ref().map(|v| v.as_str()));
+                chat_settings.remove(&(upstream_type.to_owned(), origin.chat_id.to_owned()));
+                upstream.reply(client, &origin, answer)
+            }
+            Admin(command) => {
+                let answer = process_admin_command(&data.conn, &mut data.requests, &mut data.paused,
+                                                   upstream_type, &**upstream, client, command);
+                data.scheduler.forget_unlinked(&data.conn, &data.requests);
+                upstream.reply(client, &origin, answer)
+            }
+            Invalid { reason } => upstream.reply(client, &origin, reason),
+        }
+    }
+}
+
+/// Process updates polled from downstream account for each of its links, lookup verify messages
+/// and push new updates to upstreams
+fn process_polled(data: &mut GlobalData, connects: &Upstreams, account: &Account, poll_result: &PollResult,
+                  chat_settings: &mut HashMap<(String, String), RoomSettings>) {
+    let client = &data.http_client;
+    let now = Utc::now().naive_utc();
+    let activity = match *poll_result {
+        Ok(Polled::Updates { items: ref updates, .. }) => {
+            info!("Fetched {} items for {}", updates.len(), account.1);
+            Activity::LatestItem(updates.iter().map(|u| u.timestamp()).max())
+        }
+        Ok(Polled::Unchanged) => {
+            debug!("Nothing changed for {}", account.1);
+            Activity::Unchanged
+        }
+        Err(_) => Activity::Unknown,
+    };
+    let pending = data.requests.iter()
+        .any(|i| !i.verified && i.adapter == account.0 && i.linked_user_id == account.1);
+    data.scheduler.polled(&data.conn, account, activity, pending, now);
+
+    for user_info in &mut data.requests {
+        if user_info.adapter != account.0 || user_info.linked_user_id != account.1 {
+            continue;
+        }
+
+        // account is polled for all its links together, links in chats that don't allow the adapter
+        // get nothing, same as when operators paused it while the poll was in progress
+        let settings = load_settings(&data.conn, chat_settings, &user_info.upstream_type, &user_info.chat_id);
+        if data.paused.contains(&user_info.adapter) || !settings.adapter_allowed(user_info.adapter) {
+            continue;
+        }
+
+        let old_verified = user_info.verified;
+        let old_last_update = user_info.last_update;
+        let upstream = connects.get(&user_info.upstream_type).expect("Must be known upstream type!");
+
+        // remember how polling went for status reports
+        let status = data.statuses.entry(user_info.key()).or_insert_with(LinkStatus::default);
+        status.last_poll = Some(Utc::now().naive_utc());
+        let (updates, skipped) = match *poll_result {
+            Ok(Polled::Updates { items: ref updates, ref skipped }) => {
+                status.last_error = None;
+                status.error_count = 0;
+                (user_info.take_updates(updates), skipped)
+            }
+            Ok(Polled::Unchanged) => {
+                // nothing new to deliver or verify with
+                status.last_error = None;
+                status.error_count = 0;
+                continue;
+            }
+            Err(ref error) => {
+                error!("Error while polling {}: {}", user_info.linked_user_id, error);
+                status.last_error = Some(error.to_owned());
+                status.error_count += 1;
+                continue;
+            }
+        };
+
+        // progress is saved as it's made, so links don't go back after crashes
+        if user_info.id != 0 && user_info.last_update > old_last_update {
+            if let Err(error) = database::save_link(&data.conn, user_info) {
+                error!("Couldn't save progress of {}: {:?}", user_info.linked_user_id, error);
+            }
+        }
+
+        if !user_info.verified {
+            // don't report data for user that wasn't previously verified
+            continue
+        }
+
+        if !old_verified {
+            // this user info just got itself verified, notify and save it to DB
+            upstream.report_added_link(client, user_info);
+            user_info.id = database::save_link(&data.conn, user_info).expect("Error saving new user info!");
+        }
+
+        // Skip items that were already delivered, first batch for new links is only remembered
+        let changes = match database::take_changes(&data.conn, user_info, updates, skipped) {
+            Ok(changes) => changes,
+            Err(error) => {
+                error!("Couldn't find changes for {}: {:?}", user_info.linked_user_id, error);
+                continue;
+            }
+        };
+
+        if user_info.is_muted(Utc::now().naive_utc()) {
+            // changes are remembered as seen already, so nothing is dumped on unmute
+            continue;
+        }
+
+        // Push an update message to upstream for each new data found in adapter,
+        // keep already pushed messages in sync with downstream.
+        // New items that don't pass link filters are skipped, the rest are held back for digests,
+        // during quiet hours and over the rate limit
+        let held = if settings.digest_period().is_some() {
+            Some(HoldReason::Digest)
+        } else if settings.in_quiet_hours(Utc::now()) {
+            Some(HoldReason::QuietHours)
+        } else {
+            None
+        };
+        let link_filters = data.filters.get(&user_info.id);
+        for change in changes {
+            match change {
+                ItemChange::New(update) if !link_filters.map_or(true, |f| f.accepts(update)) => {
+                    debug!("Item {} of {} is filtered out", update.id(), user_info.linked_user_id);
+                }
+                ItemChange::New(update) => {
+                    let held = if held.is_some() {
+                        held
+                    } else if take_slot(&mut data.sent, &user_info.upstream_type, &user_info.chat_id,
+                                        settings.rate_limit()) {
+                        None
+                    } else {
+                        Some(HoldReason::RateLimit)
+                    };
+                    if let Some(reason) = held {
+                        if let Err(error) = database::queue_digest_item(&data.conn, user_info, update, reason) {
+                            error!("Couldn't hold back item {}: {:?}", update.id(), error);
+                        }
+                        continue;
                     }
-                    Unlink(user_info) => data.requests.retain(|i| i != &user_info),
-                    UnlinkAll { user_name, upstream_type } => {
-                        data.requests.retain(|i| i.user_id == user_name && i.upstream_type == upstream_type)
+
+                    let item_id = update.id();
+                    let event_id = match upstream.push_update(client, user_info, &settings, update) {
+                        None => continue,
+                        Some(event_id) => event_id,
+                    };
+                    if let Err(error) = database::remember_event(&data.conn, user_info, &item_id, &event_id) {
+                        error!("Couldn't remember event {} for item {}: {:?}", event_id, item_id, error);
                     }
-                    Explain { chat_id, command } => upstream.explain_command(client, &chat_id, &command)
                 }
+                ItemChange::Edited { message_id, update } => {
+                    upstream.edit_update(client, user_info, &settings, &message_id, update)
+                }
+                ItemChange::Deleted { message_id } => upstream.delete_update(client, user_info, &message_id),
             }
         }
+    }
+}
 
-        // process any updates from downstream adapters, lookup verify messages
-        for user_info in &mut data.requests {
-            let old_verified = user_info.verified;
-            let upstream = data.connects.get(&user_info.upstream_type).expect("Must be known upstream type!");
-            let updates = user_info.poll(&data.http_client);
+/// Tell operators that source went down or recovered, in the chat configured for that
+fn notify_operators(data: &GlobalData, connects: &Upstreams, notice: &str) {
+    warn!("{}", notice);
+    let (upstream_type, chat_id) = match data.notify_chat {
+        None => return,
+        Some((ref upstream_type, ref chat_id)) => (upstream_type, chat_id),
+    };
+    let upstream = match connects.get(upstream_type) {
+        None => return,
+        Some(upstream) => upstream,
+    };
 
-            if !user_info.verified {
-                // don't report data for user that wasn't previously verified
-                continue
+    let origin = Origin {
+        chat_id: chat_id.to_owned(),
+        user_id: String::new(),
+        message_id: None,
+    };
+    upstream.reply(&data.http_client, &origin, notice.to_owned());
+}
+
+/// Persist threads upstreams started, so updates keep going to them after restart
+fn save_thread_roots(conn: &SqliteConnection, connects: &Upstreams) {
+    for upstream in connects.values() {
+        for root in upstream.take_new_thread_roots() {
+            if let Err(error) = database::save_thread_root(conn, &root) {
+                error!("Couldn't save thread {} of {}: {:?}", root.thread_key, root.chat_id, error);
             }
+        }
+    }
+}
 
+/// Settings of the chat, loaded from DB once per event loop cycle
+fn load_settings(conn: &SqliteConnection, cache: &mut HashMap<(String, String), RoomSettings>, upstream_type: &str,
+                 chat_id: &str) -> RoomSettings {
+    let key = (upstream_type.to_owned(), chat_id.to_owned());
+    cache.entry(key)
+        .or_insert_with(|| {
+            database::load_settings(conn, upstream_type, chat_id).unwrap_or_else(|error| {
+                error!("Couldn't load settings for {}: {:?}", chat_id, error);
+                RoomSettings::default()
+            })
+        })
+        .clone()
+}
 
-            if !old_verified {
-                // this user info just got itself verified, notify and insert to DB
-                upstream.report_added_link(client, user_info);
+/// Send held back updates once quiet hours of their chat are over. Updates held during quiet hours
+/// are pushed one by one as rate limit allows, so they can be edited and deleted later like any other.
+/// Chats without rate limit would get all of them at once, so they get them summarized instead.
+/// The rest are sent as one summary per chat: chats in digest mode get it once their oldest update
+/// waited for the whole digest period, others as soon as rate limit allows.
+fn send_digests(conn: &SqliteConnection, connects: &Upstreams, client: &HttpClient, requests: &[UserInfo],
+                sent: &mut HashMap<(String, String), Vec<NaiveDateTime>>,
+                chat_settings: &mut HashMap<(String, String), RoomSettings>) {
+    let items = match database::load_digest_items(conn) {
+        Ok(items) => items,
+        Err(error) => {
+            error!("Couldn't load items for digests: {:?}", error);
+            return;
+        }
+    };
 
-                // screw you, Diesel
-                let new_row = NewUserInfo {
-                    upstream_type: user_info.upstream_type.to_owned(),
-                    chat_id: user_info.chat_id.to_owned(),
-                    user_id: user_info.user_id.to_owned(),
-                    adapter: user_info.adapter,
-                    linked_user_id: user_info.linked_user_id.to_owned(),
-                    last_update: user_info.last_update
-                };
+    // items are sorted by queue time, so first item of the chat is its oldest one
+    let mut chats: Vec<((String, String), Vec<DigestItem>)> = vec![];
+    for item in items {
+        let key = (item.upstream_type.to_owned(), item.chat_id.to_owned());
+        let found = chats.iter().position(|&(ref chat, _)| *chat == key);
+        match found {
+            Some(idx) => chats[idx].1.push(item),
+            None => chats.push((key, vec![item])),
+        }
+    }
+
+    let now = Utc::now().naive_utc();
+    for ((upstream_type, chat_id), items) in chats {
+        let settings = load_settings(conn, chat_settings, &upstream_type, &chat_id);
+        if settings.in_quiet_hours(Utc::now()) {
+            continue;
+        }
+        let upstream = match connects.get(&upstream_type) {
+            None => continue,
+            Some(upstream) => upstream,
+        };
+
+        let (individual, summarized): (Vec<DigestItem>, Vec<DigestItem>) = match settings.rate_limit() {
+            None => (vec![], items),
+            Some(_) => items.into_iter().partition(|item| item.hold_reason() == HoldReason::QuietHours),
+        };
+        let pushed_all = push_held_items(conn, &**upstream, client, requests, sent, &settings, individual);
+        if !pushed_all || summarized.is_empty() {
+            // summary goes after the updates that came before it
+            continue;
+        }
+
+        let due = settings.digest_period().map_or(true, |period| summarized[0].queued_at + period <= now);
+        if !due || !take_slot(sent, &upstream_type, &chat_id, settings.rate_limit()) {
+            continue;
+        }
+        if !upstream.push_digest(client, &chat_id, &settings, &summarized) {
+            continue;
+        }
+
+        let ids: Vec<i32> = summarized.iter().map(|i| i.id).collect();
+        if let Err(error) = database::delete_digest_items(conn, &ids) {
+            error!("Couldn't forget items sent in digest to {}: {:?}", chat_id, error);
+        }
+    }
+}
+
+/// Push updates held back during quiet hours of the chat one by one, while rate limit allows.
+/// Returns true if all of them are pushed
+fn push_held_items(conn: &SqliteConnection, upstream: &Upstream, client: &HttpClient, requests: &[UserInfo],
+                   sent: &mut HashMap<(String, String), Vec<NaiveDateTime>>, settings: &RoomSettings,
+                   items: Vec<DigestItem>) -> bool {
+    for item in items {
+        let link = match requests.iter().find(|i| i.id == item.user_info_id) {
+            Some(link) => link,
+            None => {
+                // unlinked meanwhile, its items are deleted along with it
+                continue;
+            }
+        };
+        if !take_slot(sent, &item.upstream_type, &item.chat_id, settings.rate_limit()) {
+            return false;
+        }
+
+        let event_id = match upstream.push_update(client, link, settings, &item) {
+            None => return false, // try again next cycle
+            Some(event_id) => event_id,
+        };
+        if let Err(error) = database::remember_event(conn, link, &item.item_id, &event_id) {
+            error!("Couldn't remember event {} for item {}: {:?}", event_id, item.item_id, error);
+        }
+        if let Err(error) = database::delete_digest_items(conn, &[item.id]) {
+            error!("Couldn't forget item {} pushed to {}: {:?}", item.item_id, item.chat_id, error);
+        }
+    }
+    true
+}
+
+/// Check whether one more message can be pushed to the chat under its rate limit and count it if so
+fn take_slot(sent: &mut HashMap<(String, String), Vec<NaiveDateTime>>, upstream_type: &str, chat_id: &str,
+             limit: Option<usize>) -> bool {
+    let limit = match limit {
+        None => return true,
+        Some(limit) => limit,
+    };
+
+    let now = Utc::now().naive_utc();
+    let recent = sent.entry((upstream_type.to_owned(), chat_id.to_owned())).or_insert_with(Vec::new);
+    recent.retain(|sent_at| *sent_at > now - chrono::Duration::minutes(1));
+    if recent.len() >= limit {
+        return false;
+    }
+    recent.push(now);
+    true
+}
+
+/// Mute the link until the time or unmute it if time is not set, returns answer for the user
+fn mute(conn: &SqliteConnection, requests: &mut Vec<UserInfo>, request: &UserInfo, until: Option<NaiveDateTime>)
+        -> String {
+    let link = match requests.iter_mut().find(|i| *i == request) {
+        None => return format!("There's no link to {} here", request.linked_user_id),
+        Some(link) => link,
+    };
+    if !link.verified {
+        return format!("Link to {} is not verified yet", link.linked_user_id);
+    }
+
+    if let Err(error) = database::set_muted(conn, link, until) {
+        error!("Couldn't mute {}: {:?}", link.linked_user_id, error);
+        return format!("Couldn't change mute of {}", link.linked_user_id);
+    }
+    link.muted_until = until;
+
+    match until {
+        None => format!("Unmuted {}", link.linked_user_id),
+        Some(until) if until == muted_forever() => format!("Muted {} until unmuted", link.linked_user_id),
+        Some(until) => format!("Muted {} until {}", link.linked_user_id, until),
+    }
+}
+
+/// Turn tracking of replies to the linked account on or off, returns answer for the user
+fn track_replies(conn: &SqliteConnection, link: &mut UserInfo, track: bool) -> String {
+    if let Err(error) = database::set_track_replies(conn, link, track) {
+        error!("Couldn't change reply tracking of {}: {:?}", link.linked_user_id, error);
+        return format!("Couldn't change reply tracking of {}", link.linked_user_id);
+    }
+    link.track_replies = track;
+
+    if track {
+        format!("Replies to {} are tracked now", link.linked_user_id)
+    } else {
+        format!("Replies to {} are not tracked anymore", link.linked_user_id)
+    }
+}
+
+/// Show filters of the link or change them, returns answer for the user
+fn change_filters(conn: &SqliteConnection, requests: &[UserInfo], filters: &mut HashMap<i32, Filters>,
+                  request: &UserInfo, change: Option<FilterChange>) -> String {
+    let link = match requests.iter().find(|i| *i == request) {
+        None => return format!("There's no link to {} here", request.linked_user_id),
+        Some(link) => link,
+    };
+    if !link.verified {
+        return format!("Link to {} is not verified yet", link.linked_user_id);
+    }
+
+    if let Some(change) = change {
+        match database::change_filters(conn, link, &change) {
+            Ok(changed) => {
+                filters.insert(link.id, changed);
+            }
+            Err(error) => {
+                error!("Couldn't change filters of {}: {:?}", link.linked_user_id, error);
+                return format!("Couldn't change filters of {}", link.linked_user_id);
+            }
+        }
+    }
+
+    let current = filters.get(&link.id).map_or("no filters".to_owned(), |f| f.describe());
+    format!("Filters of {}: {}", link.linked_user_id, current)
+}
+
+/// Show settings of the chat or change one of them, returns answer for the user
+fn change_settings(conn: &SqliteConnection, upstream_type: &str, chat_id: &str, name: Option<&str>,
+                   value: Option<&str>) -> String {
+    if let (Some(name), Some(value)) = (name, value) {
+        let new_value = if value == "default" { None } else { Some(value) };
+        if let Err(error) = database::save_setting(conn, upstream_type, chat_id, name, new_value) {
+            error!("Couldn't save setting {} for {}: {:?}", name, chat_id, error);
+            return format!("Couldn't change {}", name);
+        }
+    }
+
+    match database::load_settings(conn, upstream_type, chat_id) {
+        Ok(settings) => settings::describe(&settings, name),
+        Err(error) => {
+            error!("Couldn't load settings for {}: {:?}", chat_id, error);
+            "Couldn't load settings of this chat".to_owned()
+        }
+    }
+}
+
+/// Remove the link from DB and from polled requests, returns answer for the user
+fn unlink(conn: &SqliteConnection, requests: &mut Vec<UserInfo>, request: &UserInfo) -> String {
+    {
+        let link = match requests.iter().find(|i| *i == request) {
+            None => return format!("There's no link to {} here", request.linked_user_id),
+            Some(link) => link,
+        };
+
+        if let Err(error) = database::delete_link(conn, link) {
+            error!("Couldn't delete link to {}: {:?}", link.linked_user_id, error);
+            return format!("Couldn't unlink {}", link.linked_user_id);
+        }
+    }
+
+    requests.retain(|i| i != request);
+    format!("Unlinked {}", request.linked_user_id)
+}
+
+/// Remove all links of the user in the chat, or in all chats of this upstream if chat is not set.
+///
+/// Unless confirmed, only reports what would be removed. Returns answer for the user.
+fn unlink_all(conn: &SqliteConnection, requests: &mut Vec<UserInfo>, upstream_type: &str, user_id: &str,
+              chat_id: Option<&str>, confirmed: bool, prefix: &str) -> String {
+    let matches = |i: &UserInfo| {
+        i.upstream_type == upstream_type && i.user_id == user_id && chat_id.map_or(true, |c| i.chat_id == c)
+    };
+    let describe = |i: &UserInfo| format!("{}/{} → {}", i.adapter.to_string(), i.linked_user_id, i.chat_id);
+
+    let scope = if chat_id.is_some() { "in this chat" } else { "anywhere" };
+    let affected: Vec<String> = requests.iter().filter(|i| matches(*i)).map(|i| describe(i)).collect();
+    if affected.is_empty() {
+        return format!("You have no links {}", scope);
+    }
+
+    if !confirmed {
+        let everywhere = if chat_id.is_some() { "" } else { " everywhere" };
+        return format!("This will unlink:\n{}\nRepeat with {}unlinkall{} confirm to proceed",
+                       affected.join("\n"), prefix, everywhere);
+    }
+
+    // drop from memory only what was actually deleted from DB, so they stay in sync
+    let mut removed: Vec<String> = vec![];
+    let mut removed_keys: Vec<String> = vec![];
+    let mut failed: Vec<String> = vec![];
+    for link in requests.iter().filter(|i| matches(*i)) {
+        match database::delete_link(conn, link) {
+            Ok(_) => {
+                removed.push(describe(link));
+                removed_keys.push(link.key());
+            }
+            Err(error) => {
+                error!("Couldn't delete link to {}: {:?}", link.linked_user_id, error);
+                failed.push(describe(link));
+            }
+        }
+    }
+    requests.retain(|i| !removed_keys.contains(&i.key()));
+
+    let mut answer = format!("Unlinked {} of {} links {}", removed.len(), affected.len(), scope);
+    if !removed.is_empty() {
+        answer = answer + &format!(":\n{}", removed.join("\n"));
+    }
+    if !failed.is_empty() {
+        answer = answer + &format!("\nCouldn't unlink:\n{}", failed.join("\n"));
+    }
+    answer
+}
 
-                diesel::insert(&new_row)
-                    .into(user_info::table)
-                    .execute(&data.conn).expect("Error saving new user info!");
+/// Process bot-wide command issued by operator, returns answer for the operator.
+///
+/// Links are referred to by their ids in DB, which don't change when other links come and go.
+fn process_admin_command(conn: &SqliteConnection, requests: &mut Vec<UserInfo>, paused: &mut HashSet<Adapter>,
+                         upstream_type: &str, upstream: &Upstream, client: &HttpClient, command: AdminCommand)
+                         -> String {
+    match command {
+        AdminCommand::Links => {
+            let lines: Vec<String> = requests.iter()
+                .map(|i| {
+                    format!("#{}: {} {} in {} {} by {}{}", i.id, i.adapter.to_string(), i.linked_user_id,
+                            i.upstream_type, i.chat_id, i.user_id, if i.verified { "" } else { ", pending" })
+                })
+                .collect();
+            if lines.is_empty() {
+                return "There are no links".to_owned();
+            }
+            format!("All links:\n{}", lines.join("\n"))
+        }
+        AdminCommand::Unlink(id) => {
+            let idx = match requests.iter().position(|i| i.id == id) {
+                None => return format!("There's no link #{}", id),
+                Some(idx) => idx,
+            };
+            if let Err(error) = database::delete_link(conn, &requests[idx]) {
+                return format!("Couldn't delete link #{}: {}", id, error);
+            }
+            let removed = requests.remove(idx);
+            format!("Removed link to {} by {}", removed.linked_user_id, removed.user_id)
+        }
+        AdminCommand::Verify(id) => {
+            let link = match requests.iter_mut().find(|i| i.id == id) {
+                None => return format!("There's no link #{}", id),
+                Some(link) => link,
+            };
+            if link.verified {
+                return format!("Link #{} is already verified", id);
+            }
+            link.verified = true;
+            link.id = match database::save_link(conn, link) {
+                Ok(saved) => saved,
+                Err(error) => {
+                    link.verified = false;
+                    return format!("Couldn't save link #{}: {}", id, error);
+                }
+            };
+            if link.upstream_type == upstream_type {
+                upstream.report_added_link(client, link);
+            }
+            format!("Link to {} by {} is verified", link.linked_user_id, link.user_id)
+        }
+        AdminCommand::Pause(adapter) => {
+            paused.insert(adapter);
+            format!("Polling of {} is paused", adapter.to_string())
+        }
+        AdminCommand::Resume(adapter) => {
+            paused.remove(&adapter);
+            format!("Polling of {} is resumed", adapter.to_string())
+        }
+        AdminCommand::Leave(chat_id) => {
+            if let Err(error) = upstream.leave_chat(client, &chat_id) {
+                return format!("Couldn't leave {}: {}", chat_id, error);
             }
 
-            // Push an update message to upstream for each new data found in adapter
-            for update in updates {
-                upstream.push_update(client, &user_info.chat_id, update);
+            let mut dropped = 0;
+            for link in requests.iter().filter(|i| i.upstream_type == upstream_type && i.chat_id == chat_id) {
+                match database::delete_link(conn, link) {
+                    Ok(_) => dropped += 1,
+                    Err(error) => error!("Couldn't delete link to {}: {:?}", link.linked_user_id, error),
+                }
+            }
+            requests.retain(|i| i.upstream_type != upstream_type || i.chat_id != chat_id);
+            format!("Left {}, dropped {} links", chat_id, dropped)
+        }
+        AdminCommand::Ban(user_id) => {
+            match database::ban_user(conn, upstream_type, &user_id) {
+                Ok(true) => format!("{} is banned", user_id),
+                Ok(false) => format!("{} is already banned", user_id),
+                Err(error) => format!("Couldn't ban {}: {}", user_id, error),
+            }
+        }
+        AdminCommand::Unban(user_id) => {
+            match database::unban_user(conn, upstream_type, &user_id) {
+                Ok(true) => format!("{} is not banned anymore", user_id),
+                Ok(false) => format!("{} wasn't banned", user_id),
+                Err(error) => format!("Couldn't unban {}: {}", user_id, error),
             }
+        }
+        AdminCommand::Stats => {
+            let verified = requests.iter().filter(|i| i.verified).count();
+            let chats: HashSet<(&str, &str)> = requests.iter()
+                .map(|i| (i.upstream_type.as_str(), i.chat_id.as_str()))
+                .collect();
+            let (banned, seen) = database::count_stats(conn).unwrap_or_else(|error| {
+                error!("Couldn't count stats: {:?}", error);
+                (0, 0)
+            });
+            let paused_names: Vec<String> = paused.iter().map(|a| a.to_string()).collect();
+            format!("Links: {} verified, {} pending, in {} chats\nDelivered items: {}\nBanned users: {}\n\
+                     Paused adapters: {}",
+                    verified, requests.len() - verified, chats.len(), seen, banned,
+                    if paused_names.is_empty() { "none".to_owned() } else { paused_names.join(", ") })
+        }
+    }
+}
+
+#[cfg(all(test, feature = "linux-org-ru"))]
+mod tests {
+    use super::*;
 
-            // TODO: persist last_update to db!
+    fn link(chat_id: &str, user_id: &str, linked_user_id: &str) -> UserInfo {
+        UserInfo {
+            id: 0,
+            upstream_type: "Matrix".to_owned(),
+            chat_id: chat_id.to_owned(),
+            user_id: user_id.to_owned(),
+            adapter: Adapter::LinuxOrgRu,
+            linked_user_id: linked_user_id.to_owned(),
+            last_update: NaiveDateTime::from_timestamp(0, 0),
+            verified: true,
+            track_replies: false,
+            muted_until: None,
         }
+    }
+
+    /// Links of @alice in two rooms and of @bob in one, all saved to DB
+    fn saved_links(conn: &SqliteConnection) -> Vec<UserInfo> {
+        let mut links = vec![
+            link("!room1", "@alice", "alice"),
+            link("!room1", "@alice", "alice2"),
+            link("!room2", "@alice", "alice"),
+            link("!room1", "@bob", "bob"),
+        ];
+        for link in &mut links {
+            link.id = database::save_link(conn, link).expect("Link must be saved");
+        }
+        links
+    }
+
+    fn linked_users(requests: &[UserInfo]) -> Vec<String> {
+        requests.iter().map(|i| format!("{} in {}", i.linked_user_id, i.chat_id)).collect()
+    }
 
-        debug!("Done polling, sleeping...");
-        thread::sleep(Duration::from_millis(3000));
+    #[test]
+    fn unlink_all_asks_for_confirmation() {
+        let conn = database::test_connection();
+        let mut requests = saved_links(&conn);
+
+        let answer = unlink_all(&conn, &mut requests, "Matrix", "@alice", Some("!room1"), false, "!");
+        assert_eq!(answer, "This will unlink:\nLinuxOrgRu/alice → !room1\nLinuxOrgRu/alice2 → !room1\n\
+                            Repeat with !unlinkall confirm to proceed");
+        assert_eq!(requests.len(), 4);
+
+        let answer = unlink_all(&conn, &mut requests, "Matrix", "@alice", Some("!room1"), true, "!");
+        assert_eq!(answer, "Unlinked 2 of 2 links in this chat:\n\
+                            LinuxOrgRu/alice → !room1\nLinuxOrgRu/alice2 → !room1");
+        assert_eq!(linked_users(&requests), vec!["alice in !room2", "bob in !room1"]);
+
+        let answer = unlink_all(&conn, &mut requests, "Matrix", "@alice", Some("!room1"), true, "!");
+        assert_eq!(answer, "You have no links in this chat");
+    }
+
+    #[test]
+    fn unlink_all_everywhere() {
+        let conn = database::test_connection();
+        let mut requests = saved_links(&conn);
+
+        let answer = unlink_all(&conn, &mut requests, "Matrix", "@alice", None, false, "!");
+        assert!(answer.ends_with("Repeat with !unlinkall everywhere confirm to proceed"));
+        assert_eq!(requests.len(), 4);
+
+        let answer = unlink_all(&conn, &mut requests, "Matrix", "@alice", None, true, "!");
+        assert!(answer.starts_with("Unlinked 3 of 3 links anywhere:\n"));
+        assert!(answer.contains("LinuxOrgRu/alice → !room2"));
+        assert_eq!(linked_users(&requests), vec!["bob in !room1"]);
+
+        // removed links are gone from DB too
+        let saved: Vec<UserInfo> = user_info::table.load(&conn).unwrap();
+        assert_eq!(linked_users(&saved), vec!["bob in !room1"]);
+
+        // links in other upstreams are left alone
+        let answer = unlink_all(&conn, &mut requests, "Telegram", "@bob", None, true, "!");
+        assert_eq!(answer, "You have no links anywhere");
+        assert_eq!(requests.len(), 1);
+    }
+
+    /// Downstream item with just what `take_changes` looks at
+    struct Item {
+        id: &'static str,
+        kind: UpdateKind,
+        timestamp: NaiveDateTime,
+        hash: &'static str,
+        old_hash: Option<&'static str>,
+    }
+
+    impl UpdateDesc for Item {
+        fn fields(&self) -> UpdateFields {
+            UpdateFields::default()
+        }
+
+        fn timestamp(&self) -> NaiveDateTime {
+            self.timestamp
+        }
+
+        fn kind(&self) -> UpdateKind {
+            self.kind
+        }
+
+        fn id(&self) -> String {
+            self.id.to_owned()
+        }
+
+        fn topic_id(&self) -> String {
+            "topic".to_owned()
+        }
+
+        fn content_hash(&self) -> String {
+            self.hash.to_owned()
+        }
+
+        fn old_content_hash(&self) -> Option<String> {
+            self.old_hash.map(|hash| hash.to_owned())
+        }
+    }
+
+    fn item(id: &'static str, kind: UpdateKind, timestamp: NaiveDateTime) -> Item {
+        Item { id: id, kind: kind, timestamp: timestamp, hash: "", old_hash: None }
+    }
+
+    fn edited(id: &'static str, timestamp: NaiveDateTime, hash: &'static str, old_hash: &'static str) -> Item {
+        Item { hash: hash, old_hash: Some(old_hash), ..item(id, UpdateKind::Post, timestamp) }
+    }
+
+    fn new_ids(changes: &[ItemChange]) -> Vec<String> {
+        changes.iter()
+            .filter_map(|change| match *change {
+                ItemChange::New(update) => Some(update.id()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tracking_replies_doesnt_push_old_ones() {
+        let conn = database::test_connection();
+        let mut links = saved_links(&conn);
+        let link = &mut links[0];
+        let long_ago = NaiveDate::from_ymd(2017, 11, 1).and_hms(12, 0, 0);
+        let post = item("post", UpdateKind::Post, long_ago);
+        let post: &UpdateDesc = &post;
+        database::take_changes(&conn, link, vec![post], &[]).unwrap();
+
+        assert_eq!(track_replies(&conn, link, true), "Replies to alice are tracked now");
+        let old_reply = item("old-reply", UpdateKind::Reply, long_ago);
+        let old_reply: &UpdateDesc = &old_reply;
+        let changes = database::take_changes(&conn, link, vec![post, old_reply], &[]).unwrap();
+        assert!(new_ids(&changes).is_empty());
+
+        // replies made after tracking was turned on are pushed
+        let new_reply = item("new-reply", UpdateKind::Reply, Utc::now().naive_utc() + chrono::Duration::hours(1));
+        let new_reply: &UpdateDesc = &new_reply;
+        let changes = database::take_changes(&conn, link, vec![post, old_reply, new_reply], &[]).unwrap();
+        assert_eq!(new_ids(&changes), vec!["new-reply"]);
+    }
+
+    #[test]
+    fn skipped_posts_arent_deleted() {
+        let conn = database::test_connection();
+        let links = saved_links(&conn);
+        let link = &links[0];
+        let first = item("1", UpdateKind::Post, NaiveDate::from_ymd(2017, 11, 1).and_hms(12, 0, 0));
+        let second = item("2", UpdateKind::Post, NaiveDate::from_ymd(2017, 11, 2).and_hms(12, 0, 0));
+        let third = item("3", UpdateKind::Post, NaiveDate::from_ymd(2017, 11, 3).and_hms(12, 0, 0));
+        let (first, second, third): (&UpdateDesc, &UpdateDesc, &UpdateDesc) = (&first, &second, &third);
+        database::take_changes(&conn, link, vec![first, second, third], &[]).unwrap();
+        database::remember_event(&conn, link, "2", "$event2").unwrap();
+
+        // adapter couldn't parse the second post, it's still there
+        let changes = database::take_changes(&conn, link, vec![first, third], &["2".to_owned()]).unwrap();
+        assert!(changes.is_empty());
+
+        let changes = database::take_changes(&conn, link, vec![first, third], &[]).unwrap();
+        assert_eq!(changes.len(), 1);
+        match changes[0] {
+            ItemChange::Deleted { ref message_id } => assert_eq!(message_id, "$event2"),
+            _ => panic!("Second post must be deleted"),
+        }
+    }
+
+    #[test]
+    fn rehashed_items_arent_edited() {
+        let conn = database::test_connection();
+        let links = saved_links(&conn);
+        let link = &links[0];
+        let date = NaiveDate::from_ymd(2017, 11, 1).and_hms(12, 0, 0);
+        let post = Item { hash: "old", ..item("post", UpdateKind::Post, date) };
+        database::take_changes(&conn, link, vec![&post as &UpdateDesc], &[]).unwrap();
+        database::remember_event(&conn, link, "post", "$event").unwrap();
+
+        // hashed differently by the new version, but the same
+        let rehashed = edited("post", date, "new", "old");
+        let changes = database::take_changes(&conn, link, vec![&rehashed as &UpdateDesc], &[]).unwrap();
+        assert!(changes.is_empty());
+
+        let changed = edited("post", date, "newer", "older");
+        let changes = database::take_changes(&conn, link, vec![&changed as &UpdateDesc], &[]).unwrap();
+        assert_eq!(changes.len(), 1);
+        match changes[0] {
+            ItemChange::Edited { ref message_id, .. } => assert_eq!(message_id, "$event"),
+            _ => panic!("Post must be edited"),
+        }
+    }
+
+    #[test]
+    fn take_slot_stops_at_limit() {
+        let mut sent = HashMap::new();
+        for _ in 0..3 {
+            assert!(take_slot(&mut sent, "Matrix", "!room1", Some(3)));
+        }
+        assert!(!take_slot(&mut sent, "Matrix", "!room1", Some(3)));
+        assert!(!take_slot(&mut sent, "Matrix", "!room1", Some(3)));
+        assert_eq!(sent[&("Matrix".to_owned(), "!room1".to_owned())].len(), 3);
+
+        // other chats have limits of their own
+        assert!(take_slot(&mut sent, "Matrix", "!room2", Some(3)));
+        assert!(take_slot(&mut sent, "Telegram", "!room1", Some(3)));
+        // chats without limit are never stopped
+        assert!(take_slot(&mut sent, "Matrix", "!room1", None));
+
+        // slots free up after a minute
+        for sent_at in sent.get_mut(&("Matrix".to_owned(), "!room1".to_owned())).unwrap() {
+            *sent_at = *sent_at - chrono::Duration::minutes(1);
+        }
+        assert!(take_slot(&mut sent, "Matrix", "!room1", Some(3)));
+        assert_eq!(sent[&("Matrix".to_owned(), "!room1".to_owned())].len(), 1);
     }
 }
@@ -0,0 +1,299 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use futures::future::Shared;
+use reqwest::StatusCode;
+use reqwest::header::{ETag, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, LastModified};
+use reqwest::unstable::async::{Client, Decoder};
+use tokio_core::reactor::{Handle, Timeout};
+
+use entities::*;
+use health::Health;
+use http;
+use http::HttpConfig;
+
+/// Pages fetched less than this ago are served from memory without asking the server
+const FRESH_FOR: u64 = 30;
+
+/// Pages that weren't requested for this long are forgotten, should be longer than any polling interval
+const EXPIRE_AFTER: u64 = 2 * 60 * 60;
+
+/// Page as returned by the cache
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub body: Rc<String>,
+    /// false if page is the same as it was on the previous fetch of this URL,
+    /// adapters that are the only ones fetching the URL can skip parsing it then
+    pub modified: bool,
+}
+
+/// What is known about the page fetched before
+struct Entry {
+    etag: Option<EntityTag>,
+    last_modified: Option<HttpDate>,
+    body: Rc<String>,
+    fetched_at: Instant,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    /// requests in progress, so the same URL requested concurrently is fetched once
+    pending: HashMap<String, Shared<Box<Future<Item = Page, Error = CoreError>>>>,
+    /// clients by hosts they're configured for, see `HttpConfig::async_client_for`
+    clients: HashMap<String, Client>,
+}
+
+/// HTTP cache shared by adapters on polling reactor.
+///
+/// Remembers ETag and Last-Modified of fetched pages and sends conditional requests, so unchanged pages
+/// aren't downloaded again. Pages fetched very recently are not requested at all.
+/// Requests to hosts that are down fail without being sent, the rest time out after configured timeout
+/// and how they went is recorded in `health`.
+/// Clones share the same cache.
+#[derive(Clone)]
+pub struct HttpCache {
+    handle: Handle,
+    config: Rc<HttpConfig>,
+    health: Arc<Mutex<Health>>,
+    state: Rc<RefCell<CacheState>>,
+    /// pages fetched less than this ago are not requested again
+    fresh_for: Duration,
+}
+
+impl HttpCache {
+
+    pub fn new(handle: Handle, config: HttpConfig, health: Arc<Mutex<Health>>) -> HttpCache {
+        HttpCache {
+            handle: handle,
+            config: Rc::new(config),
+            health: health,
+            state: Rc::new(RefCell::new(CacheState::default())),
+            fresh_for: Duration::from_secs(FRESH_FOR),
+        }
+    }
+
+    /// Client configured for the host, clients are built once per host
+    fn client_for(&self, state: &mut CacheState, host: &str) -> Result<Client> {
+        if let Some(client) = state.clients.get(host) {
+            return Ok(client.clone());
+        }
+
+        let client = self.config.async_client_for(host, &self.handle)?;
+        state.clients.insert(host.to_owned(), client.clone());
+        Ok(client)
+    }
+
+    /// Fetch the page, answers from memory if it's fresh and asks the server whether it changed otherwise
+    pub fn fetch(&self, url: &str) -> Box<Future<Item = Page, Error = CoreError>> {
+        let mut state = self.state.borrow_mut();
+        state.entries.retain(|_, entry| entry.fetched_at.elapsed() < Duration::from_secs(EXPIRE_AFTER));
+        if let Some(entry) = state.entries.get(url) {
+            if entry.fetched_at.elapsed() < self.fresh_for {
+                return Box::new(future::ok(Page { body: entry.body.clone(), modified: false }));
+            }
+        }
+        if let Some(pending) = state.pending.get(url) {
+            return shared_page(pending.clone());
+        }
+
+        let client = http::host_of(url)
+            .and_then(|host| http::check_health(&self.health, &host).map(|_| host))
+            .and_then(|host| self.client_for(&mut *state, &host).map(|client| (host, client)));
+        let (host, client) = match client {
+            Ok(client) => client,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        let deadline = match Timeout::new(self.config.timeout, &self.handle) {
+            Ok(deadline) => deadline,
+            Err(error) => return Box::new(future::err(error.into())),
+        };
+        let mut request = match client.get(url) {
+            Ok(request) => request,
+            Err(error) => return Box::new(future::err(error.into())),
+        };
+        if let Some(entry) = state.entries.get(url) {
+            if let Some(ref etag) = entry.etag {
+                request.header(IfNoneMatch::Items(vec![etag.clone()]));
+            }
+            if let Some(ref date) = entry.last_modified {
+                request.header(IfModifiedSince(date.clone()));
+            }
+        }
+
+        let response = request.send()
+            .and_then(|mut response| {
+                let status = response.status();
+                let etag = response.headers().get::<ETag>().map(|etag| etag.0.clone());
+                let last_modified = response.headers().get::<LastModified>().map(|date| date.0.clone());
+                let body = mem::replace(response.body_mut(), Decoder::empty()).concat2();
+                body.map(move |body| (status, etag, last_modified, body))
+            })
+            .from_err::<CoreError>()
+            .select(deadline.from_err::<CoreError>().and_then(|_| Err(CoreError::CustomError("Timed out".to_owned()))))
+            .map(|(response, _)| response)
+            .map_err(|(error, _)| error);
+
+        let cache = self.state.clone();
+        let health = self.health.clone();
+        let url = url.to_owned();
+        let pending_url = url.clone();
+        let page = response.then(move |response| {
+            let mut state = cache.borrow_mut();
+            state.pending.remove(&url);
+            let answer = match response {
+                Ok(ref response) => Ok(response.0),
+                Err(ref error) => Err(error.to_string()),
+            };
+            http::record_health(&health, &host, answer);
+            let (status, etag, last_modified, body) = response?;
+
+            if status == StatusCode::NotModified {
+                let entry = match state.entries.get_mut(&url) {
+                    None => return Err(CoreError::CustomError(format!("{} is not modified, but never fetched", url))),
+                    Some(entry) => entry,
+                };
+                entry.fetched_at = Instant::now();
+                return Ok(Page { body: entry.body.clone(), modified: false });
+            }
+            if !status.is_success() {
+                return Err(CoreError::CustomError(format!("{} answered with {}", url, status)));
+            }
+
+            let body = String::from_utf8(body.to_vec())
+                .map_err(|error| CoreError::CustomError(format!("{} is not valid UTF-8: {}", url, error)))?;
+            let body = Rc::new(body);
+            let entry = Entry {
+                etag: etag,
+                last_modified: last_modified,
+                body: body.clone(),
+                fetched_at: Instant::now(),
+            };
+            state.entries.insert(url, entry);
+            Ok(Page { body: body, modified: true })
+        });
+
+        let shared = (Box::new(page) as Box<Future<Item = Page, Error = CoreError>>).shared();
+        state.pending.insert(pending_url, shared.clone());
+        shared_page(shared)
+    }
+}
+
+/// Page from the request shared by everyone who asked for the URL
+fn shared_page(shared: Shared<Box<Future<Item = Page, Error = CoreError>>>)
+               -> Box<Future<Item = Page, Error = CoreError>> {
+    Box::new(shared.map(|page| (*page).clone()).map_err(|error| CoreError::CustomError(error.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use chrono;
+    use config::Config;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    /// Serve `requests` requests to the page with ETag on local port, requests that have this ETag already
+    /// are answered with 304. Returns URL of the page and how many requests were served
+    fn serve(requests: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/page", listener.local_addr().unwrap());
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = served.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let response = if request.contains("if-none-match: \"v1\"") {
+                    "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n"
+                } else {
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello"
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, served)
+    }
+
+    fn cache(core: &Core, health: &Arc<Mutex<Health>>) -> HttpCache {
+        HttpCache::new(core.handle(), HttpConfig::load(&Config::new()).unwrap(), health.clone())
+    }
+
+    fn health() -> Arc<Mutex<Health>> {
+        Arc::new(Mutex::new(Health::new(1, chrono::Duration::minutes(5))))
+    }
+
+    #[test]
+    fn fresh_pages_are_not_requested() {
+        let (url, served) = serve(1);
+        let mut core = Core::new().unwrap();
+        let cache = cache(&core, &health());
+
+        let page = core.run(cache.fetch(&url)).unwrap();
+        assert_eq!((page.body.as_str(), page.modified), ("hello", true));
+        let page = core.run(cache.fetch(&url)).unwrap();
+        assert_eq!((page.body.as_str(), page.modified), ("hello", false));
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn stale_pages_are_requested_conditionally() {
+        let (url, served) = serve(2);
+        let mut core = Core::new().unwrap();
+        let mut cache = cache(&core, &health());
+        cache.fresh_for = Duration::from_secs(0);
+
+        let page = core.run(cache.fetch(&url)).unwrap();
+        assert_eq!((page.body.as_str(), page.modified), ("hello", true));
+        // server answers 304, body is taken from memory
+        let page = core.run(cache.fetch(&url)).unwrap();
+        assert_eq!((page.body.as_str(), page.modified), ("hello", false));
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn concurrent_fetches_share_request() {
+        let (url, served) = serve(1);
+        let mut core = Core::new().unwrap();
+        let cache = cache(&core, &health());
+
+        let (first, second) = core.run(cache.fetch(&url).join(cache.fetch(&url))).unwrap();
+        assert_eq!(first.body.as_str(), "hello");
+        assert_eq!(second.body.as_str(), "hello");
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn hosts_that_are_down_are_not_requested() {
+        let (url, served) = serve(1);
+        let mut core = Core::new().unwrap();
+        let health = health();
+        health.lock().unwrap().failed("127.0.0.1", "timed out", chrono::Utc::now().naive_utc());
+        let cache = cache(&core, &health);
+
+        assert!(core.run(cache.fetch(&url)).is_err());
+        assert_eq!(served.load(Ordering::SeqCst), 0);
+    }
+}
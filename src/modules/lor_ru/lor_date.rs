@@ -0,0 +1,197 @@
+use chrono::prelude::*;
+use chrono::Duration;
+
+use entities::Result;
+use entities::CoreError;
+
+/// LOR shows all dates in Moscow time, which is UTC+3 all year round since 2014
+const MSK_OFFSET_SECS: i32 = 3 * 3600;
+
+/// Numeric formats LOR uses for dates in message signatures.
+/// Two-digit years go first, `%Y` would happily read `17` as year 17
+const NUMERIC_FORMATS: [&str; 4] = ["%d.%m.%y %H:%M:%S", "%d.%m.%y %H:%M", "%d.%m.%Y %H:%M:%S", "%d.%m.%Y %H:%M"];
+
+/// Month names in genitive case, as in "1 октября 2017"
+const MONTHS: [&str; 12] = ["января", "февраля", "марта", "апреля", "мая", "июня",
+                            "июля", "августа", "сентября", "октября", "ноября", "декабря"];
+
+/// Moscow timezone
+pub fn msk() -> FixedOffset {
+    FixedOffset::east(MSK_OFFSET_SECS)
+}
+
+/// Current time in Moscow, what all relative dates on LOR are relative to
+pub fn msk_now() -> DateTime<FixedOffset> {
+    Utc::now().with_timezone(&msk())
+}
+
+/// Parse date of LOR comment or topic into UTC date.
+///
+/// `datetime` attribute of `<time>` element is preferred as it's machine-readable,
+/// if it's absent or broken, the text of the element is parsed. Text may be:
+/// * numeric, e.g. `01.10.17 21:42:00`
+/// * textual, e.g. `1 октября 2017 г. 21:42`
+/// * relative to `now`, e.g. `сегодня 21:42`, `вчера 21:42`, `5 минут назад`, `час назад`, `только что`
+///
+/// Dates without explicit offset are considered to be in Moscow time.
+pub fn parse_lor_date(datetime_attr: Option<&str>, text: &str, now: DateTime<FixedOffset>) -> Result<NaiveDateTime> {
+    if let Some(datetime) = datetime_attr {
+        match DateTime::parse_from_rfc3339(datetime.trim()) {
+            Ok(date) => return Ok(date.naive_utc()),
+            Err(error) => debug!("Malformed datetime attribute '{}': {}, trying text", datetime, error),
+        }
+    }
+
+    let words: Vec<String> = text.split_whitespace().map(|w| w.to_lowercase()).collect();
+    let normalized = words.join(" ");
+    if normalized.is_empty() {
+        return Err(CoreError::DateError("Date is missing both datetime attribute and text".to_owned()));
+    }
+
+    let local = parse_relative(&words, now)
+        .or_else(|| parse_numeric(&normalized))
+        .or_else(|| parse_textual(&words));
+
+    match local {
+        None => Err(CoreError::DateError(format!("Unknown date format: '{}'", normalized))),
+        Some(naive) => {
+            let date = msk().from_local_datetime(&naive).single()
+                .ok_or_else(|| CoreError::DateError(format!("Ambiguous date: '{}'", normalized)))?;
+            Ok(date.naive_utc())
+        }
+    }
+}
+
+/// Parse dates relative to current time, returns local Moscow time
+fn parse_relative(words: &[String], now: DateTime<FixedOffset>) -> Option<NaiveDateTime> {
+    let today = now.naive_local().date();
+    let words: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+    if words.len() != 2 && words.len() != 3 {
+        return None;
+    }
+
+    match (words[0], words[1]) {
+        ("только", "что") => return Some(now.naive_local()),
+        ("сегодня", time) => return parse_time(time).map(|t| today.and_time(t)),
+        ("вчера", time) => return parse_time(time).map(|t| (today - Duration::days(1)).and_time(t)),
+        ("минуту", "назад") => return Some(now.naive_local() - Duration::minutes(1)),
+        ("час", "назад") => return Some(now.naive_local() - Duration::hours(1)),
+        _ => {}
+    }
+
+    // "5 минут назад", "2 часа назад"
+    if words.len() != 3 || words[2] != "назад" {
+        return None;
+    }
+
+    let amount: i64 = match words[0].parse() {
+        Ok(amount) => amount,
+        Err(_) => return None,
+    };
+    let unit = words[1];
+    let elapsed = if unit.starts_with("секунд") {
+        Duration::seconds(amount)
+    } else if unit.starts_with("минут") {
+        Duration::minutes(amount)
+    } else if unit.starts_with("час") {
+        Duration::hours(amount)
+    } else {
+        return None;
+    };
+    Some(now.naive_local() - elapsed)
+}
+
+/// Parse numeric dates like `01.10.17 21:42:00`
+fn parse_numeric(text: &str) -> Option<NaiveDateTime> {
+    NUMERIC_FORMATS.iter()
+        .filter_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .next()
+}
+
+/// Parse textual dates like `1 октября 2017 г. 21:42`
+fn parse_textual(words: &[String]) -> Option<NaiveDateTime> {
+    // year marker is optional
+    let words: Vec<&str> = words.iter().map(|w| w.as_str()).filter(|w| *w != "г." && *w != "г").collect();
+    if words.len() != 4 {
+        return None;
+    }
+
+    let day = words[0].parse::<u32>().ok();
+    let month = MONTHS.iter().position(|m| *m == words[1]).map(|idx| idx as u32 + 1);
+    let year = words[2].trim_right_matches(',').parse::<i32>().ok();
+    let time = parse_time(words[3]);
+    match (year, month, day, time) {
+        (Some(year), Some(month), Some(day), Some(time)) => {
+            NaiveDate::from_ymd_opt(year, month, day).map(|date| date.and_time(time))
+        }
+        _ => None,
+    }
+}
+
+/// Parse time of the day, with or without seconds
+fn parse_time(text: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(text, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(text, "%H:%M"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1 October 2017, 22:00 in Moscow
+    fn now() -> DateTime<FixedOffset> {
+        msk().ymd(2017, 10, 1).and_hms(22, 0, 0)
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(year, month, day).and_hms(hour, min, sec)
+    }
+
+    fn parse(text: &str) -> NaiveDateTime {
+        parse_lor_date(None, text, now()).expect("Date must be parsed")
+    }
+
+    #[test]
+    fn relative_dates() {
+        assert_eq!(parse("только что"), utc(2017, 10, 1, 19, 0, 0));
+        assert_eq!(parse("минуту назад"), utc(2017, 10, 1, 18, 59, 0));
+        assert_eq!(parse("5 минут назад"), utc(2017, 10, 1, 18, 55, 0));
+        assert_eq!(parse("30 секунд назад"), utc(2017, 10, 1, 18, 59, 30));
+        assert_eq!(parse("час назад"), utc(2017, 10, 1, 18, 0, 0));
+        assert_eq!(parse("2 часа назад"), utc(2017, 10, 1, 17, 0, 0));
+        assert_eq!(parse("сегодня 21:42"), utc(2017, 10, 1, 18, 42, 0));
+        assert_eq!(parse("Вчера   21:42:10"), utc(2017, 9, 30, 18, 42, 10));
+    }
+
+    #[test]
+    fn numeric_dates() {
+        assert_eq!(parse("01.10.17 21:42:00"), utc(2017, 10, 1, 18, 42, 0));
+        assert_eq!(parse("01.10.2017 21:42"), utc(2017, 10, 1, 18, 42, 0));
+        assert_eq!(parse("01.10.17 01:15"), utc(2017, 9, 30, 22, 15, 0));
+    }
+
+    #[test]
+    fn textual_dates() {
+        assert_eq!(parse("1 октября 2017 г. 21:42"), utc(2017, 10, 1, 18, 42, 0));
+        assert_eq!(parse("12 мая 2016, 08:05:30"), utc(2016, 5, 12, 5, 5, 30));
+        assert!(parse_lor_date(None, "31 февраля 2017 г. 21:42", now()).is_err());
+    }
+
+    #[test]
+    fn datetime_attribute() {
+        let date = parse_lor_date(Some("2017-10-01T21:42:00+03:00"), "whatever", now());
+        assert_eq!(date.unwrap(), utc(2017, 10, 1, 18, 42, 0));
+
+        // broken attribute falls back to text
+        let date = parse_lor_date(Some("yesterday"), "сегодня 21:42", now());
+        assert_eq!(date.unwrap(), utc(2017, 10, 1, 18, 42, 0));
+    }
+
+    #[test]
+    fn unknown_dates() {
+        assert!(parse_lor_date(None, "", now()).is_err());
+        assert!(parse_lor_date(None, "давным-давно", now()).is_err());
+        assert!(parse_lor_date(None, "много часов назад", now()).is_err());
+    }
+}
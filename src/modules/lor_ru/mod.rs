@@ -1,15 +1,25 @@
 use std::vec::Vec;
-
-use reqwest::Client;
+use std::collections::HashSet;
+use futures::{future, Future};
 use select::document::Document;
+use select::node::Node;
 use select::predicate::{Predicate, Attr, Class, Name};
 
 use chrono::prelude::*;
 
-use modules::UserComment;
+mod lor_date;
+
+use modules::{content_hash, sanitize_html, UserComment};
+use modules::http_cache::HttpCache;
 use entities::*;
+use self::lor_date::*;
 
 const LOR_URL: &'static str = "https://www.linux.org.ru/";
+/// Host of `LOR_URL`, its health is tracked by host
+pub const LOR_HOST: &str = "www.linux.org.ru";
+
+/// How many of the recent threads to scan for replies on each poll
+pub const MAX_SCANNED_THREADS: usize = 5;
 
 /// Lor comment struct definition
 /// TODO: Basically speaking we can track both comments and posts distinctly
@@ -17,103 +27,454 @@ pub struct LorComment {
     common: UserComment,
     post_link: String,
     author_link: String,
+    /// sanitized HTML of the comment text
+    comment_html: Option<String>,
+    /// sanitized HTML of the whole comment body, edits anywhere in it change the content hash
+    body_html: String,
 }
 
 /// How to represent it in different upstreams
 impl UpdateDesc for LorComment {
-    fn as_string(&self) -> String {
-        self.common.as_string()
-    }
-
-    fn as_markdown(&self, md_type: MarkdownType) -> String {
-        match md_type {
-            MarkdownType::Matrix | MarkdownType::GitHub => {
-                format!("{}: [{}]({}) added comment to post [{}]({}):\n\t{}",
-                        self.common.comment_date,
-                        self.common.user_name,
-                        self.author_link,
-                        self.common.post_title,
-                        self.post_link,
-                        self.common.comment_text)
-            }
-            MarkdownType::Telegram => self.as_string(),
+    fn fields(&self) -> UpdateFields {
+        UpdateFields {
+            author_url: self.author_link.to_owned(),
+            url: self.post_link.to_owned(),
+            section: extract_section(&self.post_link),
+            rich_text: self.comment_html.clone(),
+            ..self.common.fields()
         }
     }
 
-    fn as_html(&self) -> String {
-        format!("{}: <a href='{}'>{}</a> added comment to post <a href='{}'>{}</a>:<br/>{}",
-                self.common.comment_date,
-                self.author_link,
-                self.common.user_name,
-                self.post_link,
-                self.common.post_title,
-                self.common.comment_text)
+    fn timestamp(&self) -> NaiveDateTime {
+        self.common.comment_date
+    }
+
+    fn kind(&self) -> UpdateKind {
+        UpdateKind::Post
+    }
+
+    fn id(&self) -> String {
+        self.common.id()
+    }
+
+    fn topic_id(&self) -> String {
+        thread_link(&self.post_link)
+    }
+
+    fn content_hash(&self) -> String {
+        content_hash(&[&self.common.user_name, &self.common.post_title, &self.body_html])
+    }
+
+    fn old_content_hash(&self) -> Option<String> {
+        Some(self.common.content_hash())
+    }
+}
+
+/// Somebody answered linked user, either to their comment or to the topic they started
+pub struct LorReply {
+    common: UserComment,
+    post_link: String,
+    author_link: String,
+    /// sanitized HTML of the comment text
+    comment_html: Option<String>,
+    /// sanitized HTML of the whole comment body, edits anywhere in it change the content hash
+    body_html: String,
+}
+
+impl UpdateDesc for LorReply {
+    fn fields(&self) -> UpdateFields {
+        UpdateFields {
+            author_url: self.author_link.to_owned(),
+            url: self.post_link.to_owned(),
+            section: extract_section(&self.post_link),
+            rich_text: self.comment_html.clone(),
+            ..self.common.fields()
+        }
     }
 
     fn timestamp(&self) -> NaiveDateTime {
         self.common.comment_date
     }
+
+    fn kind(&self) -> UpdateKind {
+        UpdateKind::Reply
+    }
+
+    fn id(&self) -> String {
+        self.common.id()
+    }
+
+    fn topic_id(&self) -> String {
+        thread_link(&self.post_link)
+    }
+
+    fn content_hash(&self) -> String {
+        content_hash(&[&self.common.user_name, &self.common.post_title, &self.body_html])
+    }
+
+    fn old_content_hash(&self) -> Option<String> {
+        Some(self.common.content_hash())
+    }
+}
+
+/// Comments found on the search page of the user
+pub struct UserPosts {
+    pub comments: Vec<LorComment>,
+    /// ids of comments that are on the page but couldn't be parsed, they're not gone from it
+    pub skipped: Vec<String>,
 }
 
 /// Retrieve data for requested user from his profile page
 /// This doesn't show posts or comments made in secret boards but that'd defeat the purpose of
 /// having such bot anyway
-pub fn get_user_posts(user_name: &String, client: &Client) -> Result<Vec<LorComment>> {
-    let url = LOR_URL.to_string() + "search.jsp?range=COMMENTS&sort=DATE&user=" + &user_name;
-    let response = client.get(&url)?.send()?;
+///
+/// If the page didn't change since the last poll there's nothing new in it, so it's only parsed
+/// when `always_parse` is set, `None` is returned otherwise
+pub fn get_user_posts(user_name: &str, cache: &HttpCache, always_parse: bool)
+                      -> Box<Future<Item = Option<UserPosts>, Error = CoreError>> {
+    let url = LOR_URL.to_string() + "search.jsp?range=COMMENTS&sort=DATE&user=" + user_name;
+    Box::new(cache.fetch(&url).and_then(move |page| {
+        if !page.modified && !always_parse {
+            return Ok(None);
+        }
+        parse_user_posts(&Document::from(page.body.as_str())).map(Some)
+    }))
+}
 
-    let doc = Document::from_read(response).unwrap();
+/// Parse comments from search page of the user.
+///
+/// One odd comment shouldn't hide the rest of them, so comments that can't be parsed are skipped.
+/// Their ids are reported so they aren't mistaken for deleted ones.
+fn parse_user_posts(doc: &Document) -> Result<UserPosts> {
+    let now = msk_now();
     let mut comments: Vec<LorComment> = vec![];
+    let mut skipped: Vec<String> = vec![];
     for node in doc.find(Name("article").and(Class("msg"))) {
-        // extract post data
+        // extract post data, without a link comment can't even be told apart from others
         let (post_link, post_title) = match node.find(Name("h2").descendant(Name("a"))).next() {
-            None => continue,
+            None => {
+                warn!("Skipping comment without link: {}", node.attr("id").unwrap_or_default());
+                continue;
+            }
             Some(post) => {
                 let pl = post.attr("href").unwrap_or_default();
                 let pt = post.text();
                 (pl, pt)
             }
         };
+        let item_id = extract_cid(post_link).unwrap_or_else(|| post_link.to_owned());
 
-        // extract author data
-        let (author_link, author_name) = match node.find(Name("a").and(Attr("itemprop", "creator"))).next() {
-            None => continue,
-            Some(author) => {
-                let al = author.attr("href").unwrap_or_default();
-                let an = author.text();
-                (al, an)
+        // extract author and comment data
+        let author = extract_author(&node);
+        let date = extract_date(&node, now);
+        let text = extract_text(&node);
+        let body = extract_body(&node);
+        let (author_link, author_name, comment_date, comment_text, body_html) = match (author, date, text, body) {
+            (Some((al, an)), Some(Ok(date)), Some(text), Some(body)) => (al, an, date, text, body),
+            (_, Some(Err(error)), _, _) => {
+                warn!("Skipping comment in {}: {:?}", post_link, error);
+                skipped.push(item_id);
+                continue;
+            }
+            _ => {
+                warn!("Skipping incomplete comment in {}", post_link);
+                skipped.push(item_id);
+                continue;
             }
         };
 
-        // extract comment data
-        let (comment_date, comment_text);
-        match node.find(Name("time")).next() {
-            None => continue,
-            Some(time) => {
-                let instant = Local::now().with_timezone(Local::now().offset());
-                comment_date = time.attr("datetime").map_or(instant,
-                                                            |t| DateTime::parse_from_rfc3339(t).unwrap_or(instant))
-            }
+        comments.push(LorComment {
+            common: UserComment {
+                item_id: item_id,
+                user_name: author_name,
+                post_title: post_title,
+                comment_date: comment_date,
+                comment_text: comment_text,
+            },
+            post_link: lor_link(post_link),
+            author_link: lor_link(&author_link),
+            comment_html: extract_html(&node),
+            body_html: body_html,
+        });
+    }
+
+    Ok(UserPosts { comments: comments, skipped: skipped })
+}
+
+/// Scan threads that user recently commented in and find replies to them.
+///
+/// Comment is considered a reply if its "Ответ на:" header points to one of user's comments
+/// or if it has no such header and the thread was started by the user.
+/// Only a few most recent threads are scanned so we don't hammer LOR with requests,
+/// they're fetched concurrently.
+pub fn get_user_replies(user_name: &str, comments: &[LorComment], cache: &HttpCache)
+                        -> Box<Future<Item = Vec<LorReply>, Error = CoreError>> {
+    let own_cids: HashSet<String> = comments.iter().filter_map(|c| extract_cid(&c.post_link)).collect();
+
+    // comments are sorted by date, so first link to the thread is the most recent one
+    let mut scanned_threads: HashSet<String> = HashSet::new();
+    let mut pages = vec![];
+    for comment in comments {
+        if scanned_threads.len() >= MAX_SCANNED_THREADS {
+            break;
+        }
+
+        let thread_link = thread_link(&comment.post_link);
+        if !scanned_threads.insert(thread_link.clone()) {
+            continue;
         }
-        match node.find(Name("div").and(Class("msg_body")).descendant(Name("p")))
-            .next() {
+
+        // requesting with cid redirects to the page this comment is on.
+        // Other accounts may scan the same thread, so page is parsed even if it didn't change
+        let user_name = user_name.to_owned();
+        let own_cids = own_cids.clone();
+        pages.push(cache.fetch(&comment.post_link).and_then(move |page| {
+            find_replies(&Document::from(page.body.as_str()), &user_name, &thread_link, &own_cids)
+        }));
+    }
+
+    Box::new(future::join_all(pages).map(|pages| pages.into_iter().flat_map(|replies| replies).collect()))
+}
+
+/// Find replies to the user on a single thread page
+fn find_replies(doc: &Document, user_name: &str, thread_link: &str, own_cids: &HashSet<String>)
+                -> Result<Vec<LorReply>> {
+    let now = msk_now();
+    let mut topic_title = String::new();
+    let mut topic_author = String::new();
+    let mut replies: Vec<LorReply> = vec![];
+    for node in doc.find(Name("article").and(Class("msg"))) {
+        let node_id = node.attr("id").unwrap_or_default();
+
+        // topic goes first on every page of the thread
+        if node_id.starts_with("topic-") {
+            topic_title = node.find(Name("h1")).next().map(|h| h.text()).unwrap_or_default();
+            topic_author = extract_author(&node).map(|(_, name)| name).unwrap_or_default();
+            continue;
+        }
+
+        let cid = node_id.trim_left_matches("comment-");
+        let (author_link, author_name) = match extract_author(&node) {
             None => continue,
-            Some(text) => {
-                comment_text = text.text();
-            }
+            Some(author) => author,
+        };
+        if author_name == user_name {
+            // talking to yourself doesn't count
+            continue;
         }
 
-        comments.push(LorComment {
+        // "Ответ на: комментарий от user" header, contains link to the comment being answered
+        let reply_to = node.find(Class("title").descendant(Name("a")))
+            .filter_map(|a| a.attr("href"))
+            .filter_map(extract_cid)
+            .next();
+        let is_reply = match reply_to {
+            Some(ref answered_cid) => own_cids.contains(answered_cid),
+            None => topic_author == user_name,
+        };
+        if !is_reply {
+            continue;
+        }
+
+        let (comment_date, comment_text, body_html) = match (extract_date(&node, now), extract_text(&node),
+                                                             extract_body(&node)) {
+            (Some(Ok(date)), Some(text), Some(body)) => (date, text, body),
+            (Some(Err(error)), _, _) => {
+                warn!("Skipping reply {} in {}: {:?}", cid, thread_link, error);
+                continue;
+            }
+            _ => continue,
+        };
+
+        replies.push(LorReply {
             common: UserComment {
+                item_id: cid.to_owned(),
                 user_name: author_name,
-                post_title: post_title,
-                comment_date: comment_date.naive_utc(),
+                post_title: topic_title.clone(),
+                comment_date: comment_date,
                 comment_text: comment_text,
             },
-            post_link: LOR_URL.to_owned() + post_link,
-            author_link: LOR_URL.to_owned() + author_link,
+            post_link: format!("{}?cid={}", thread_link, cid),
+            author_link: lor_link(&author_link),
+            comment_html: extract_html(&node),
+            body_html: body_html,
         });
     }
 
-    Ok(comments)
+    Ok(replies)
+}
+
+/// Extract author link and name from comment or topic node
+fn extract_author(node: &Node) -> Option<(String, String)> {
+    node.find(Name("a").and(Attr("itemprop", "creator"))).next().map(|author| {
+        let al = author.attr("href").unwrap_or_default().to_owned();
+        let an = author.text();
+        (al, an)
+    })
+}
+
+/// Extract date when comment or topic was posted. Returns `None` if node has no date at all
+/// and error if the date is present but can't be parsed.
+fn extract_date(node: &Node, now: DateTime<FixedOffset>) -> Option<Result<NaiveDateTime>> {
+    node.find(Name("time")).next().map(|time| parse_lor_date(time.attr("datetime"), &time.text(), now))
+}
+
+/// Extract first paragraph of the comment text
+fn extract_text(node: &Node) -> Option<String> {
+    node.find(Name("div").and(Class("msg_body")).descendant(Name("p")))
+        .next()
+        .map(|text| text.text())
+}
+
+/// Extract first paragraph of the comment with formatting, sanitized as it's going to be sent as HTML
+fn extract_html(node: &Node) -> Option<String> {
+    node.find(Name("div").and(Class("msg_body")).descendant(Name("p")))
+        .next()
+        .map(|text| sanitize_html(&text, LOR_URL))
+}
+
+/// Extract the whole comment body with formatting, sanitized so markup that is never shown doesn't matter
+fn extract_body(node: &Node) -> Option<String> {
+    node.find(Name("div").and(Class("msg_body")))
+        .next()
+        .map(|body| sanitize_html(&body, LOR_URL))
+}
+
+/// Extract comment id from the link, e.g. `/forum/talks/13701522?cid=13703076`
+fn extract_cid(link: &str) -> Option<String> {
+    link.split("cid=").nth(1).map(|cid| cid.split('&').next().unwrap_or_default().to_owned())
+}
+
+/// Strip comment id from the comment link to get link to the thread it's in
+fn thread_link(comment_link: &str) -> String {
+    comment_link.split('?').next().unwrap_or_default().to_owned()
+}
+
+/// Extract section of the site from the link, e.g. `talks` from `/forum/talks/13701522`
+/// or `news` from `/news/linux-general/13700000`
+fn extract_section(link: &str) -> String {
+    let mut segments = link.trim_left_matches(LOR_URL).trim_left_matches('/').split('/');
+    match segments.next() {
+        Some("forum") => segments.next().unwrap_or_default().to_owned(),
+        Some(section) => section.to_owned(),
+        None => String::new(),
+    }
+}
+
+/// Make absolute LOR link from relative one
+fn lor_link(path: &str) -> String {
+    LOR_URL.to_owned() + path.trim_left_matches('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use select::predicate::Any;
+
+    use templates::{self, TemplateFormat};
+    use super::*;
+
+    /// Search page where titles, authors, links and sections carry markup and scripts
+    const HOSTILE_SEARCH: &str = include_str!("fixtures/hostile_search.html");
+
+    const EVENT_HANDLERS: &[&str] = &["onerror", "onload", "onmouseover", "onclick"];
+
+    fn hostile_comments() -> Vec<LorComment> {
+        parse_user_posts(&Document::from(HOSTILE_SEARCH)).expect("Fixture must be parsed").comments
+    }
+
+    /// Check that rendered HTML has no active content and only links to LOR
+    fn assert_inert(html: &str) {
+        let doc = Document::from(html);
+        for tag in &["script", "svg", "img", "iframe"] {
+            assert_eq!(doc.find(Name(*tag)).count(), 0, "<{}> in {}", tag, html);
+        }
+        for node in doc.find(Any) {
+            for handler in EVENT_HANDLERS {
+                assert!(node.attr(handler).is_none(), "{} attribute in {}", handler, html);
+            }
+        }
+        for href in doc.find(Name("a")).filter_map(|link| link.attr("href")) {
+            assert!(href.starts_with(LOR_URL), "Link to '{}' in {}", href, html);
+        }
+    }
+
+    #[test]
+    fn hostile_fields_are_parsed_as_text() {
+        let comments = hostile_comments();
+        assert_eq!(comments.len(), 2);
+
+        let fields = comments[0].fields();
+        assert_eq!(fields.title, "<script>alert('title')</script>");
+        assert_eq!(fields.author, "<img src=x onerror=alert('author')>");
+        assert_eq!(fields.section, "\"'><svg onload=alert('section')>");
+        assert_eq!(fields.author_url, "https://www.linux.org.ru/people/evil' onerror='alert(1)/profile");
+
+        let fields = comments[1].fields();
+        assert_eq!(fields.url, "https://www.linux.org.ru/javascript:alert('link')//forum/talks/13701522?cid=13703077");
+        assert_eq!(fields.author_url, "https://www.linux.org.ru/javascript:alert('author link')");
+    }
+
+    #[test]
+    fn hostile_fields_are_escaped_in_html() {
+        let comments = hostile_comments();
+
+        let html = comments[0].as_html();
+        assert_inert(&html);
+        assert!(html.contains(">&lt;script&gt;alert(&#39;title&#39;)&lt;/script&gt;</a>"), "{}", html);
+        assert!(html.contains(">&lt;img src=x onerror=alert(&#39;author&#39;)&gt;</a>"), "{}", html);
+        assert!(html.contains("href='https://www.linux.org.ru/people/evil&#39; onerror=&#39;alert(1)/profile'"),
+                "{}", html);
+        assert!(html.contains("Ничего &lt;b onmouseover=alert(1)&gt; особенного"), "{}", html);
+
+        let html = comments[1].as_html();
+        assert_inert(&html);
+        assert!(html.contains(">Link &quot;quoted&quot; &amp; &lt;b&gt;bold&lt;/b&gt;</a>"), "{}", html);
+        assert!(html.contains(">&quot;&gt;&lt;svg/onload=alert(1)&gt;</a>"), "{}", html);
+        assert!(html.contains("<a>ссылка</a>"), "{}", html);
+    }
+
+    #[test]
+    fn hostile_section_is_escaped_in_html() {
+        let fields = hostile_comments()[0].fields();
+        let html = templates::render("<b>{section}</b>: {title}", &fields, TemplateFormat::Html);
+        assert_inert(&html);
+        assert_eq!(html, "<b>&quot;&#39;&gt;&lt;svg onload=alert(&#39;section&#39;)&gt;</b>: \
+                          &lt;script&gt;alert(&#39;title&#39;)&lt;/script&gt;");
+    }
+
+    fn search_page(articles: &[(&str, &str, &str)]) -> Document {
+        let articles: Vec<String> = articles.iter().map(|&(cid, date, body)| {
+            format!("<article class=\"msg\"><h2><a href=\"/forum/talks/1?cid={}\">Topic</a></h2>\
+                     <div class=\"msg_body\">{}</div>\
+                     <a itemprop=\"creator\" href=\"/people/user/profile\">user</a>\
+                     <time>{}</time></article>", cid, body, date)
+        }).collect();
+        Document::from(format!("<html><body>{}</body></html>", articles.concat()).as_str())
+    }
+
+    #[test]
+    fn unparseable_comments_are_reported() {
+        let doc = search_page(&[
+            ("1", "когда-то давно", "<p>odd date</p>"),
+            ("2", "01.10.17 21:42:00", "no paragraphs"),
+            ("3", "01.10.17 21:43:00", "<p>fine</p>"),
+        ]);
+        let posts = parse_user_posts(&doc).unwrap();
+        let ids: Vec<String> = posts.comments.iter().map(|c| c.id()).collect();
+        assert_eq!(ids, vec!["3"]);
+        assert_eq!(posts.skipped, vec!["1", "2"]);
+    }
+
+    #[test]
+    fn edits_anywhere_in_body_change_hash() {
+        let hashes = |body: &str| {
+            let posts = parse_user_posts(&search_page(&[("1", "01.10.17 21:42:00", body)])).unwrap();
+            (posts.comments[0].content_hash(), posts.comments[0].old_content_hash())
+        };
+        let (hash, old_hash) = hashes("<p>first</p><p>second</p>");
+        let (edited_hash, edited_old_hash) = hashes("<p>first</p><p>second, edited</p>");
+        assert!(hash != edited_hash);
+        // older versions only hashed the first paragraph
+        assert_eq!(old_hash, edited_old_hash);
+    }
 }
@@ -1,16 +1,15 @@
 use std::io::Read;
 
-use reqwest::Client;
-
 use entities::Result;
 use entities::CoreError;
+use http::HttpClient;
 
 const MANKIER_ENDPOINT: &str = "https://www.mankier.com/api/v2/explain/?q=";
 
-pub fn explain_command(client: &Client, command: &str) -> Result<String> {
+pub fn explain_command(client: &HttpClient, command: &str) -> Result<String> {
     let req_url = MANKIER_ENDPOINT.to_owned() + command;
 
-    let mut response = client.get(&req_url)?.send()?;
+    let mut response = client.get(&req_url)?;
     if !response.status().is_success() {
         return Err(CoreError::CustomError(format!("Explain returned invalid code: {}", response.status())));
     }
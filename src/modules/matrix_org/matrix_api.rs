@@ -172,6 +172,28 @@ struct FileInfo {
     size: u64,
 }
 
+/// Relation of this event to some other event, e.g. this event is edit of that one
+#[derive(Serialize, Deserialize)]
+struct RelatesTo {
+    /// Type of the relation, e.g. `m.replace` for edits or `m.thread` for threads
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rel_type: Option<String>,
+
+    /// Event this one relates to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event_id: Option<String>,
+
+    /// Event this one replies to
+    #[serde(rename = "m.in_reply_to", skip_serializing_if = "Option::is_none")]
+    in_reply_to: Option<InReplyTo>,
+}
+
+/// Reference to the event that is being replied to
+#[derive(Serialize, Deserialize)]
+struct InReplyTo {
+    event_id: String,
+}
+
 }
 
 #[derive(Serialize, Deserialize)]
@@ -296,6 +318,14 @@ pub(super) enum MessageEventContent {
         body: String,
         format: Option<String>,
         formatted_body: Option<String>,
+
+        /// For edits, the content that replaces content of the original event
+        #[serde(rename = "m.new_content", skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<MessageEventContent>>,
+
+        /// Relation to other events, e.g. edits
+        #[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
     },
 
     /// This message represents a single image and an optional thumbnail.
@@ -1,95 +1,258 @@
-use reqwest::Client;
-
 use config::Config;
 
 use serde_json;
-use chrono::prelude::*;
 use uuid::Uuid;
 
 use std::collections::HashMap;
+use std::mem;
+use std::sync::{Mutex, RwLock};
 
 mod matrix_api;
 
 use entities::*;
+use http::HttpClient;
+use commands;
+use digest;
+use settings::{MessageFormat, RoomSettings};
+use templates;
+use templates::TemplateFormat;
 use modules::mankier;
 use self::matrix_api::*;
 
 const MATRIX_API_ENDPOINT: &str = "https://matrix.org/_matrix/client/r0";
+const MATRIX_HTML_FORMAT: &str = "org.matrix.custom.html";
+
+/// We start Matrix commands with exclamation mark
+/// because slash is reserved with server communication
+const MATRIX_COMMAND_PREFIX: &str = "!";
+
+/// How to group updates into Matrix threads, configured with `matrix.threads` property
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThreadMode {
+    /// Every update is a standalone message
+    Off,
+    /// Updates from the same linked user go to the same thread
+    PerUser,
+    /// Updates from the same downstream topic go to the same thread
+    PerTopic,
+}
+
+impl Default for ThreadMode {
+    fn default() -> Self {
+        ThreadMode::Off
+    }
+}
 
+/// Matrix upstream. Session is opened and synced by sync thread, dispatcher only reads the access token,
+/// so locks are held just long enough to copy or update the state
 #[derive(Default)]
 pub struct Matrix {
-    access_token: String,
-    last_batch: String,
+    access_token: RwLock<String>,
+    /// position in the event stream, only sync thread uses it, empty if bot never synced
+    last_batch: Mutex<String>,
+    thread_mode: ThreadMode,
+    /// first events of the threads, by chat and thread key
+    thread_roots: Mutex<HashMap<(String, String), String>>,
+    /// threads started since they were last taken to be persisted
+    new_thread_roots: Mutex<Vec<NewThreadRoot>>,
+}
+
+impl Matrix {
+
+    /// Matrix upstream configured with `matrix` section of config, continuing sync and threads
+    /// where bot left them before
+    pub fn new(cfg: &Config, sync_token: Option<String>, thread_roots: Vec<ThreadRoot>) -> Matrix {
+        let thread_mode = match cfg.get_str("matrix.threads").ok() {
+            Some(ref mode) if mode == "user" => ThreadMode::PerUser,
+            Some(ref mode) if mode == "topic" => ThreadMode::PerTopic,
+            _ => ThreadMode::Off,
+        };
+        let thread_roots = thread_roots.into_iter().map(|r| ((r.chat_id, r.thread_key), r.event_id)).collect();
+        Matrix {
+            last_batch: Mutex::new(sync_token.unwrap_or_default()),
+            thread_mode: thread_mode,
+            thread_roots: Mutex::new(thread_roots),
+            ..Default::default()
+        }
+    }
+
+    /// Access token of the current session, empty if not connected
+    fn token(&self) -> String {
+        self.access_token.read().expect("Matrix session must not be poisoned!").clone()
+    }
+
+    /// Key of the thread in the link's chat this update should go to, if updates are grouped into threads
+    fn thread_key(&self, link: &UserInfo, update: &UpdateDesc) -> Option<String> {
+        match self.thread_mode {
+            ThreadMode::Off => None,
+            ThreadMode::PerUser => Some(format!("{}/{}", link.adapter.to_string(), link.linked_user_id)),
+            ThreadMode::PerTopic => Some(update.topic_id()),
+        }
+    }
 }
 
 impl Upstream for Matrix {
 
-    fn connect(&mut self, client: &Client, cfg: &Config) {
-        if self.access_token.is_empty() {
-            self.access_token = connect(client, cfg).unwrap_or_default()
+    fn connect(&self, client: &HttpClient, cfg: &Config) {
+        if self.token().is_empty() {
+            // only sync thread connects, so nobody logs in meanwhile
+            let token = connect(client, cfg).unwrap_or_default();
+            *self.access_token.write().expect("Matrix session must not be poisoned!") = token;
+        }
+    }
+
+    fn disconnect(&self, client: &HttpClient) {
+        let token = self.token();
+        if token.is_empty() {
+            return;
+        }
+        match logout(client, &token) {
+            Ok(_) => info!("Logged out of Matrix"),
+            Err(error) => error!("Couldn't log out of Matrix: {:?}", error),
         }
+        self.access_token.write().expect("Matrix session must not be poisoned!").clear();
+    }
+
+    fn check_updates(&self, client: &HttpClient) -> Result<Vec<UpstreamCommand>> {
+        // sync request takes a while, state isn't locked for it so reading the token doesn't wait.
+        // Only the sync thread moves it, so it can't change meanwhile
+        let mut last_batch = self.sync_token();
+        let result = process_updates(client, &self.token(), &mut last_batch);
+        *self.last_batch.lock().expect("Matrix sync state must not be poisoned!") = last_batch;
+        result
     }
 
-    fn check_updates(&mut self, client: &Client) -> Result<Vec<UpstreamUpdate>> {
-        process_updates(client, &self.access_token, &mut self.last_batch)
+    fn sync_token(&self) -> String {
+        self.last_batch.lock().expect("Matrix sync state must not be poisoned!").clone()
     }
 
-    fn push_update(&self, client: &Client, chat_id: &str, update: Box<UpdateDesc>) {
-        let result = post_update(client, &self.access_token, chat_id, update);
+    fn push_update(&self, client: &HttpClient, link: &UserInfo, settings: &RoomSettings, update: &UpdateDesc)
+                   -> Option<String> {
+        let mention = mention_for(client, link, update);
+        let thread_key = self.thread_key(link, update).map(|key| (link.chat_id.to_owned(), key));
+        let thread_root = thread_key.as_ref().and_then(|key| {
+            self.thread_roots.lock().expect("Matrix thread roots must not be poisoned!").get(key).cloned()
+        });
+        let result = post_update(client, &self.token(), &link.chat_id, update, settings, mention,
+                                 thread_root.as_ref().map(|root| root.as_str()));
         match result {
-            Ok(event_id) => info!("Message posted with event id {}", event_id),
-            Err(error) => error!("Error while sending Matrix message: {:?}", error),
+            Ok(event_id) => {
+                info!("Message posted with event id {}", event_id);
+                if let (Some(key), None) = (thread_key, thread_root) {
+                    // this message starts a new thread
+                    self.new_thread_roots.lock()
+                        .expect("Matrix thread roots must not be poisoned!")
+                        .push(NewThreadRoot {
+                            upstream_type: "Matrix".to_owned(),
+                            chat_id: key.0.to_owned(),
+                            thread_key: key.1.to_owned(),
+                            event_id: event_id.to_owned(),
+                        });
+                    self.thread_roots.lock()
+                        .expect("Matrix thread roots must not be poisoned!")
+                        .insert(key, event_id.to_owned());
+                }
+                Some(event_id)
+            }
+            Err(error) => {
+                error!("Error while sending Matrix message: {:?}", error);
+                None
+            }
         }
     }
 
-    fn report_duplicate_link(&self, client: &Client, link: UserInfo) {
-        let display_name = get_display_name(client, &link.user_id).unwrap_or(link.user_id.to_owned());
-        let message = format!("{}: Link to {} is already present!", display_name, link.linked_user_id);
-        let result = post_plain_message(client, &self.access_token, &link.chat_id, message);
+    fn edit_update(&self, client: &HttpClient, link: &UserInfo, settings: &RoomSettings, message_id: &str,
+                   update: &UpdateDesc) {
+        let mention = mention_for(client, link, update);
+        let result = edit_update(client, &self.token(), &link.chat_id, message_id, update, settings, mention);
         match result {
-            Ok(event_id) => info!("Message posted with event id {}", event_id),
-            Err(error) => error!("Error while sending Matrix message: {:?}", error),
+            Ok(event_id) => info!("Message {} edited with event id {}", message_id, event_id),
+            Err(error) => error!("Error while editing Matrix message: {:?}", error),
         }
     }
 
-    fn report_link_to_verify(&self, client: &Client, link: &UserInfo) {
-        let display_name = get_display_name(client, &link.user_id).unwrap_or(link.user_id.to_owned());
-        let message = format!("{}: You should prove it's you! Write '{}' without quotes in {}!", display_name, CHALLENGE, link.adapter.to_string());
-        let result = post_plain_message(client, &self.access_token, &link.chat_id, message);
+    fn delete_update(&self, client: &HttpClient, link: &UserInfo, message_id: &str) {
+        let reason = format!("Deleted in {}", link.adapter.to_string());
+        let result = redact_event(client, &self.token(), &link.chat_id, message_id, &reason);
         match result {
-            Ok(event_id) => info!("Message posted with event id {}", event_id),
-            Err(error) => error!("Error while sending Matrix message: {:?}", error),
+            Ok(event_id) => info!("Message {} redacted with event id {}", message_id, event_id),
+            Err(error) => error!("Error while redacting Matrix message: {:?}", error),
         }
     }
 
-    fn report_added_link(&self, client: &Client, link: &UserInfo) {
+    fn report_duplicate_link(&self, client: &HttpClient, origin: &Origin, link: UserInfo) {
+        let display_name = get_display_name(client, &link.user_id).unwrap_or(link.user_id.to_owned());
+        let message = format!("{}: Link to {} is already present!", display_name, link.linked_user_id);
+        self.reply(client, origin, message);
+    }
+
+    fn report_link_to_verify(&self, client: &HttpClient, origin: &Origin, link: &UserInfo) {
+        let display_name = get_display_name(client, &link.user_id).unwrap_or(link.user_id.to_owned());
+        let message = format!("{}: You should prove it's you! Write '{}' without quotes in {}!", display_name, CHALLENGE, link.adapter.to_string());
+        self.reply(client, origin, message);
+    }
+
+    fn report_added_link(&self, client: &HttpClient, link: &UserInfo) {
         let display_name = get_display_name(client, &link.user_id).unwrap_or(link.user_id.to_owned());
         let message = format!("{}: Link to {} created!", display_name, link.linked_user_id);
-        let result = post_plain_message(client, &self.access_token, &link.chat_id, message);
+        let result = post_plain_message(client, &self.token(), &link.chat_id, message);
         match result {
             Ok(event_id) => info!("Message posted with event id {}", event_id),
             Err(error) => error!("Error while sending Matrix message: {:?}", error),
         }
     }
 
-    fn explain_command(&self, client: &Client, chat_id: &str, command: &str) {
+    fn explain_command(&self, client: &HttpClient, origin: &Origin, command: &str) {
         let explanation = mankier::explain_command(client, command);
-        let result = match explanation {
+        match explanation {
             Err(error) => {
                 error!("Error while trying to explain shell command: {:?}", error);
                 let message = format!("Couldn't explain command: {}", error);
-                post_plain_message(client, &self.access_token, chat_id, message)
+                self.reply(client, origin, message)
             }
-            Ok(explanation) => post_plain_message(client, &self.access_token, chat_id, explanation)
-        };
+            Ok(explanation) => self.reply(client, origin, explanation),
+        }
+    }
+
+    fn reply(&self, client: &HttpClient, origin: &Origin, message: String) {
+        let result = post_reply(client, &self.token(), origin, message);
         match result {
             Ok(event_id) => info!("Message posted with event id {}", event_id),
             Err(error) => error!("Error while sending Matrix message: {:?}", error),
         }
     }
+
+    fn command_prefix(&self) -> &'static str {
+        MATRIX_COMMAND_PREFIX
+    }
+
+    fn power_level(&self, client: &HttpClient, chat_id: &str, user_id: &str) -> Result<i32> {
+        get_power_level(client, &self.token(), chat_id, user_id)
+    }
+
+    fn leave_chat(&self, client: &HttpClient, chat_id: &str) -> Result<()> {
+        leave_room(client, &self.token(), chat_id)
+    }
+
+    fn push_digest(&self, client: &HttpClient, chat_id: &str, settings: &RoomSettings, items: &[DigestItem]) -> bool {
+        match post_digest(client, &self.token(), chat_id, settings, items) {
+            Ok(event_id) => {
+                info!("Digest of {} items posted with event id {}", items.len(), event_id);
+                true
+            }
+            Err(error) => {
+                error!("Error while sending Matrix digest: {:?}", error);
+                false
+            }
+        }
+    }
+
+    fn take_new_thread_roots(&self) -> Vec<NewThreadRoot> {
+        mem::replace(&mut *self.new_thread_roots.lock().expect("Matrix thread roots must not be poisoned!"), vec![])
+    }
 }
 
-pub fn connect(client: &Client, conf: &Config) -> Result<String> {
+pub fn connect(client: &HttpClient, conf: &Config) -> Result<String> {
     let user = conf.get_str("matrix.login").expect("matrix.login property must be supplied in config");
     let password = conf.get_str("matrix.password").expect("matrix.password property must be supplied in config");
     let post_body = Login {
@@ -100,7 +263,7 @@ pub fn connect(client: &Client, conf: &Config) -> Result<String> {
 
     let login_url = MATRIX_API_ENDPOINT.to_owned() + "/login";
     let body_json = serde_json::to_string(&post_body)?;
-    let response = client.post(&login_url)?.body(body_json).send()?;
+    let response = client.post(&login_url, body_json)?;
     if !response.status().is_success() {
         return Err(CoreError::CustomError(format!("Connect returned invalid code: {}", response.status())));
     }
@@ -112,7 +275,9 @@ pub fn connect(client: &Client, conf: &Config) -> Result<String> {
 /// Get all updates since last batch from Matrix servers. This requires auth.
 ///
 /// - Also join any room if invited
-pub fn process_updates(client: &Client, token: &String, last_batch: &mut String) -> Result<Vec<UpstreamUpdate>> {
+/// - Without last batch this is the first sync ever, its timeline holds commands that are
+///   long handled or stale, so they're skipped
+pub fn process_updates(client: &HttpClient, token: &String, last_batch: &mut String) -> Result<Vec<UpstreamCommand>> {
     // sync is the main routine in matrix.org lifecycle
     let sync_url = MATRIX_API_ENDPOINT.to_owned() + "/sync";
     let mut request_url = sync_url + "?access_token=" + token;
@@ -120,23 +285,29 @@ pub fn process_updates(client: &Client, token: &String, last_batch: &mut String)
         request_url = request_url + "&since=" + last_batch;
     }
 
-    let response = client.get(&request_url)?.send()?;
+    let response = client.get(&request_url)?;
     if !response.status().is_success() {
         return Err(CoreError::CustomError(format!("Connect returned invalid code: {}", response.status())));
     }
 
     // receive sync object - events, invites etc
     let response_body: SyncAnswer = serde_json::from_reader(response)?;
+    let first_sync = last_batch.is_empty();
     *last_batch = response_body.next_batch;
 
     // process invites
     if !response_body.rooms.invite.is_empty() {
         for room_id in response_body.rooms.invite.keys() {
             let join_url = format!("{base}/join/{room_id}?access_token={token}", base = MATRIX_API_ENDPOINT, room_id = room_id,token = token);
-            client.post(&join_url)?.send()?;
+            client.post(&join_url, "{}")?;
         }
     }
 
+    if first_sync {
+        info!("First Matrix sync, skipping {} rooms with old messages", response_body.rooms.join.len());
+        return Ok(Vec::default());
+    }
+
     // process link/unlink requests
     if !response_body.rooms.join.is_empty() {
         return capture_commands(response_body.rooms.join);
@@ -148,8 +319,8 @@ pub fn process_updates(client: &Client, token: &String, last_batch: &mut String)
 /// Retrieves and parses commands from room updates.
 ///
 /// Skips any updates that are not `m.message` type.
-fn capture_commands(all_rooms: HashMap<String, RoomJoinState>) -> Result<Vec<UpstreamUpdate>> {
-    let mut all_updates: Vec<UpstreamUpdate> = vec![];
+fn capture_commands(all_rooms: HashMap<String, RoomJoinState>) -> Result<Vec<UpstreamCommand>> {
+    let mut all_updates: Vec<UpstreamCommand> = vec![];
     for room_events in all_rooms {
         let room_id = room_events.0;
         let room_status = room_events.1.timeline;
@@ -159,67 +330,71 @@ fn capture_commands(all_rooms: HashMap<String, RoomJoinState>) -> Result<Vec<Ups
                 _ => continue,
             };
 
-            // we start Matrix commands with exclamation mark
-            // because slash is reserved with server communication
-            if !body.starts_with("!") {
+            if !body.starts_with(MATRIX_COMMAND_PREFIX) {
                 continue;
             }
 
-            let arguments: Vec<&str> = body.trim_left_matches("!").split(" ").collect();
-            match parse_command(&room_id, event, arguments) {
-                Some(update) => all_updates.push(update),
-                None => warn!("Couldn't parse command: {}", body),
+            // remember where the command came from so we can reply to it
+            let origin = Origin {
+                chat_id: room_id.to_owned(),
+                user_id: event.sender.to_owned(),
+                message_id: Some(event.event_id.to_owned()),
+            };
+
+            let command_text = body.trim_left_matches(MATRIX_COMMAND_PREFIX);
+            let update = commands::parse_command("Matrix", MATRIX_COMMAND_PREFIX, &origin, command_text);
+            if let UpstreamUpdate::Invalid { ref reason } = update {
+                warn!("Couldn't parse command: {}: {}", body, reason);
             }
+            all_updates.push(UpstreamCommand { origin: origin, update: update });
         }
     }
 
     return Ok(all_updates);
 }
 
-/// parse command and build upstream update entity from it if command is valid
-fn parse_command(room_id: &str, event: Event, mut arguments: Vec<&str>) -> Option<UpstreamUpdate> {
-    if arguments.is_empty() {
-        return None;
+/// Replies should mention the user so they get notified, find out how to mention them
+fn mention_for<'a>(client: &HttpClient, link: &'a UserInfo, update: &UpdateDesc) -> Option<(&'a str, String)> {
+    match update.kind() {
+        UpdateKind::Reply => {
+            let display_name = get_display_name(client, &link.user_id).unwrap_or(link.user_id.to_owned());
+            Some((link.user_id.as_str(), display_name))
+        }
+        UpdateKind::Post => None,
     }
+}
 
-    // helper lambda to build UserInfo from event + command
-    let info_from_event = |event: Event, args: &Vec<&str>| {
-        if args.len() < 2 {
-            return None;
+/// Renders update as plain and HTML body with templates of the chat,
+/// HTML body is omitted if chat settings ask for plain text.
+///
+/// If `mention` with user id and display name is supplied, message is prefixed with it
+/// so clients highlight it for that user.
+///
+/// Messages longer than `max_length` setting are cut and sent as plain text,
+/// as cutting HTML may leave broken markup.
+fn render_update(update: &UpdateDesc, settings: &RoomSettings, mention: Option<(&str, String)>)
+                 -> (String, Option<String>) {
+    let body = templates::render_update(update, settings, TemplateFormat::Plain);
+    let formatted_body = templates::render_update(update, settings, TemplateFormat::Html);
+    let (body, formatted_body) = match mention {
+        Some((user_id, display_name)) => {
+            (format!("{}: {}", display_name, body),
+             format!("<a href='https://matrix.to/#/{}'>{}</a>: {}",
+                     templates::escape_html(user_id), templates::escape_html(&display_name), formatted_body))
         }
+        None => (body, formatted_body),
+    };
 
-        let adapter: Result<Adapter> = str::parse(&args[0]);
-        let linked_user_id = args[1];
-        if adapter.is_err() {
-            return None;
+    if let Some(max_length) = settings.max_length() {
+        if body.chars().count() > max_length {
+            let cut: String = body.chars().take(max_length.saturating_sub(1)).collect();
+            return (cut + "…", None);
         }
+    }
 
-        Some(UserInfo {
-            id: 0,
-            upstream_type: "Matrix".to_owned(),
-            chat_id: room_id.to_owned(),
-            user_id: event.sender.to_owned(),
-            adapter: adapter.unwrap(),
-            linked_user_id: linked_user_id.to_owned(),
-            last_update: NaiveDateTime::from_timestamp(0, 0),
-            verified: false
-        })
-    };
-
-    match arguments.remove(0) {
-        "link" => info_from_event(event, &arguments).map(|info| UpstreamUpdate::Link(info)),
-        "unlink" => info_from_event(event, &arguments).map(|info| UpstreamUpdate::Unlink(info)),
-        "unlinkall" => Some(
-            UpstreamUpdate::UnlinkAll {
-                upstream_type: "Matrix".to_owned(),
-                user_name: event.sender,
-        }),
-        "explain" => Some(
-            UpstreamUpdate::Explain {
-                chat_id: room_id.to_owned(),
-                command: arguments.join(" ")
-        }),
-        _ => None,
+    match settings.format() {
+        MessageFormat::Html => (body, Some(formatted_body)),
+        MessageFormat::Plain => (body, None),
     }
 }
 
@@ -227,21 +402,65 @@ fn parse_command(room_id: &str, event: Event, mut arguments: Vec<&str>) -> Optio
 ///
 /// This uses undocumented `org.matrix.custom.html` format,
 /// so is subject to change in future once markdown/other formatting solution is in place.
-pub fn post_update(client: &Client, access_token: &str, chat_id: &str, update: Box<UpdateDesc>) -> Result<String> {
-    let uuid = Uuid::new_v4().hyphenated().to_string();
-    let post_msg_url = MATRIX_API_ENDPOINT.to_owned() + "/rooms/" + chat_id + "/send/m.room.message/" + &uuid +
-                       "?access_token=" + access_token;
-
+///
+/// If `thread_root` is supplied, message is posted to the thread started by that event.
+pub fn post_update(client: &HttpClient, access_token: &str, chat_id: &str, update: &UpdateDesc,
+                   settings: &RoomSettings, mention: Option<(&str, String)>, thread_root: Option<&str>)
+                   -> Result<String> {
+    let (body, formatted_body) = render_update(update, settings, mention);
     let post_content = MessageEventContent::Notice {
-        body: update.as_string(),
-        format: Some("org.matrix.custom.html".to_owned()),
-        formatted_body: Some(update.as_html()),
+        body: body,
+        format: formatted_body.as_ref().map(|_| MATRIX_HTML_FORMAT.to_owned()),
+        formatted_body: formatted_body,
+        new_content: None,
+        relates_to: thread_root.map(|root| RelatesTo {
+            rel_type: Some("m.thread".to_owned()),
+            event_id: Some(root.to_owned()),
+            in_reply_to: None,
+        }),
+    };
+    send_message_event(client, access_token, chat_id, &post_content)
+}
+
+/// Replaces the message posted for the update before with the new state of the update. This requires auth.
+///
+/// Clients that don't support edits will show it as a separate message prefixed with asterisk.
+pub fn edit_update(client: &HttpClient, access_token: &str, chat_id: &str, event_id: &str, update: &UpdateDesc,
+                   settings: &RoomSettings, mention: Option<(&str, String)>) -> Result<String> {
+    let (body, formatted_body) = render_update(update, settings, mention);
+    let new_content = MessageEventContent::Notice {
+        body: body.to_owned(),
+        format: formatted_body.as_ref().map(|_| MATRIX_HTML_FORMAT.to_owned()),
+        formatted_body: formatted_body.to_owned(),
+        new_content: None,
+        relates_to: None,
     };
-    let body_json = serde_json::to_string(&post_content)?;
+    let edit_content = MessageEventContent::Notice {
+        body: "* ".to_owned() + &body,
+        format: formatted_body.as_ref().map(|_| MATRIX_HTML_FORMAT.to_owned()),
+        formatted_body: formatted_body.map(|formatted| "* ".to_owned() + &formatted),
+        new_content: Some(Box::new(new_content)),
+        relates_to: Some(RelatesTo {
+            rel_type: Some("m.replace".to_owned()),
+            event_id: Some(event_id.to_owned()),
+            in_reply_to: None,
+        }),
+    };
+    send_message_event(client, access_token, chat_id, &edit_content)
+}
 
-    let response = client.put(&post_msg_url)?.body(body_json).send()?;
+/// Redacts event with the supplied reason. This requires auth.
+pub fn redact_event(client: &HttpClient, access_token: &str, chat_id: &str, event_id: &str, reason: &str)
+                    -> Result<String> {
+    let uuid = Uuid::new_v4().hyphenated().to_string();
+    let redact_url = MATRIX_API_ENDPOINT.to_owned() + "/rooms/" + chat_id + "/redact/" + event_id + "/" + &uuid +
+                     "?access_token=" + access_token;
+    let redact_content = EventContent::Redaction { reason: reason.to_owned() };
+    let body_json = serde_json::to_string(&redact_content)?;
+
+    let response = client.put(&redact_url, body_json)?;
     if !response.status().is_success() {
-        return Err(CoreError::CustomError(format!("Connect returned invalid code: {}", response.status())));
+        return Err(CoreError::CustomError(format!("Redact returned invalid code: {}", response.status())));
     }
 
     let mut response_body: HashMap<String, String> = serde_json::from_reader(response)?;
@@ -252,10 +471,10 @@ pub fn post_update(client: &Client, access_token: &str, chat_id: &str, update: B
 
 /// Get user display name given we know their user name slug.
 /// Auth is not required for this.
-pub fn get_display_name(client: &Client, user_name: &str) -> Result<String> {
+pub fn get_display_name(client: &HttpClient, user_name: &str) -> Result<String> {
     let get_url = MATRIX_API_ENDPOINT.to_owned() + "/profile/" + user_name + "/displayname";
 
-    let response = client.get(&get_url)?.send()?;
+    let response = client.get(&get_url)?;
     if !response.status().is_success() {
         return Err(CoreError::CustomError(format!("Matrix returned invalid code: {}", response.status())));
     }
@@ -266,19 +485,109 @@ pub fn get_display_name(client: &Client, user_name: &str) -> Result<String> {
     Ok(display_name)
 }
 
+/// Get power level of the user in the room from `m.room.power_levels` state. Requires auth.
+pub fn get_power_level(client: &HttpClient, access_token: &str, chat_id: &str, user_id: &str) -> Result<i32> {
+    let state_url = MATRIX_API_ENDPOINT.to_owned() + "/rooms/" + chat_id + "/state/m.room.power_levels" +
+                    "?access_token=" + access_token;
+
+    let response = client.get(&state_url)?;
+    if !response.status().is_success() {
+        return Err(CoreError::CustomError(format!("Matrix returned invalid code: {}", response.status())));
+    }
+
+    let content: EventContent = serde_json::from_reader(response)?;
+    match content {
+        EventContent::PowerLevels { users, users_default, .. } => {
+            Ok(users.get(user_id).cloned().unwrap_or(users_default) as i32)
+        }
+        // servers may omit some of the levels, then it doesn't match the model
+        EventContent::Other(value) => {
+            let level = value["users"][user_id].as_i64()
+                .or_else(|| value["users_default"].as_i64())
+                .unwrap_or(0);
+            Ok(level as i32)
+        }
+        _ => Err(CoreError::CustomError("Unexpected power levels content".to_owned())),
+    }
+}
+
+/// Invalidate the access token, bot has to log in again after that. Requires auth.
+pub fn logout(client: &HttpClient, access_token: &str) -> Result<()> {
+    let logout_url = MATRIX_API_ENDPOINT.to_owned() + "/logout?access_token=" + access_token;
+
+    let response = client.post(&logout_url, "{}")?;
+    if !response.status().is_success() {
+        return Err(CoreError::CustomError(format!("Logout returned invalid code: {}", response.status())));
+    }
+    Ok(())
+}
+
+/// Leave the room, bot won't receive any events from it after that. Requires auth.
+pub fn leave_room(client: &HttpClient, access_token: &str, chat_id: &str) -> Result<()> {
+    let leave_url = MATRIX_API_ENDPOINT.to_owned() + "/rooms/" + chat_id + "/leave?access_token=" + access_token;
+
+    let response = client.post(&leave_url, "{}")?;
+    if !response.status().is_success() {
+        return Err(CoreError::CustomError(format!("Leave returned invalid code: {}", response.status())));
+    }
+    Ok(())
+}
+
 /// Posts a plain `m.notice` message with requested text. Requires auth.
-pub fn post_plain_message(client: &Client, access_token: &str, chat_id: &str, message: String) -> Result<String> {
-    let uuid = Uuid::new_v4().hyphenated().to_string();
-    let post_msg_url = MATRIX_API_ENDPOINT.to_owned() + "/rooms/" + chat_id + "/send/m.room.message/" + &uuid +
-                       "?access_token=" + access_token;
+pub fn post_plain_message(client: &HttpClient, access_token: &str, chat_id: &str, message: String) -> Result<String> {
     let post_content = MessageEventContent::Notice {
         body: message,
         format: None,
         formatted_body: None,
+        new_content: None,
+        relates_to: None,
     };
-    let body_json = serde_json::to_string(&post_content)?;
+    send_message_event(client, access_token, chat_id, &post_content)
+}
 
-    let response = client.put(&post_msg_url)?.body(body_json).send()?;
+/// Posts summary of held back updates as `m.notice` message, formatted unless chat settings ask for plain text.
+/// This requires auth.
+pub fn post_digest(client: &HttpClient, access_token: &str, chat_id: &str, settings: &RoomSettings,
+                   items: &[DigestItem]) -> Result<String> {
+    let formatted_body = match settings.format() {
+        MessageFormat::Html => Some(digest::render(items, TemplateFormat::Html, settings)),
+        MessageFormat::Plain => None,
+    };
+    let post_content = MessageEventContent::Notice {
+        body: digest::render(items, TemplateFormat::Plain, settings),
+        format: formatted_body.as_ref().map(|_| MATRIX_HTML_FORMAT.to_owned()),
+        formatted_body: formatted_body,
+        new_content: None,
+        relates_to: None,
+    };
+    send_message_event(client, access_token, chat_id, &post_content)
+}
+
+/// Posts a plain `m.notice` message as a reply to the command message, if it's known. Requires auth.
+pub fn post_reply(client: &HttpClient, access_token: &str, origin: &Origin, message: String) -> Result<String> {
+    let post_content = MessageEventContent::Notice {
+        body: message,
+        format: None,
+        formatted_body: None,
+        new_content: None,
+        relates_to: origin.message_id.as_ref().map(|event_id| RelatesTo {
+            rel_type: None,
+            event_id: None,
+            in_reply_to: Some(InReplyTo { event_id: event_id.to_owned() }),
+        }),
+    };
+    send_message_event(client, access_token, &origin.chat_id, &post_content)
+}
+
+/// Sends `m.room.message` event with requested content to the room. Requires auth.
+fn send_message_event(client: &HttpClient, access_token: &str, chat_id: &str, content: &MessageEventContent)
+                      -> Result<String> {
+    let uuid = Uuid::new_v4().hyphenated().to_string();
+    let post_msg_url = MATRIX_API_ENDPOINT.to_owned() + "/rooms/" + chat_id + "/send/m.room.message/" + &uuid +
+                       "?access_token=" + access_token;
+    let body_json = serde_json::to_string(content)?;
+
+    let response = client.put(&post_msg_url, body_json)?;
     if !response.status().is_success() {
         return Err(CoreError::CustomError(format!("Connect returned invalid code: {}", response.status())));
     }
@@ -287,4 +596,4 @@ pub fn post_plain_message(client: &Client, access_token: &str, chat_id: &str, me
     let event_id = response_body.remove("event_id")
         .expect("Answer must contain event id in case of success");
     Ok(event_id)
-}
\ No newline at end of file
+}
@@ -1,15 +1,24 @@
 use chrono::prelude::*;
+use select::node::Node;
+
 use entities::UpdateDesc;
-use entities::MarkdownType;
+use entities::UpdateFields;
+use entities::UpdateKind;
+use templates::escape_html;
 
+pub mod http_cache;
 #[cfg(feature = "linux-org-ru")]
 pub mod lor_ru;
 pub mod matrix_org;
 pub mod mankier;
 
+/// How dates of updates are shown to users
+pub const DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
+
 /// Simplest generic user comment structure that may be convenient
 /// for dumb downstream adapters
 pub struct UserComment {
+    item_id: String,
     user_name: String,
     post_title: String,
     comment_date: NaiveDateTime,
@@ -17,23 +26,204 @@ pub struct UserComment {
 }
 
 impl UpdateDesc for UserComment {
-    fn as_string(&self) -> String {
-        format!("{}: {} added comment to post {}:\n\t'{}'",
-                self.comment_date,
-                self.post_title,
-                self.user_name,
-                self.comment_text)
+    fn fields(&self) -> UpdateFields {
+        UpdateFields {
+            author: self.user_name.to_owned(),
+            title: self.post_title.to_owned(),
+            text: self.comment_text.to_owned(),
+            date: self.comment_date.format(DATE_FORMAT).to_string(),
+            ..Default::default()
+        }
     }
 
-    fn as_markdown(&self, _: MarkdownType) -> String {
-        self.as_string()
+    fn timestamp(&self) -> NaiveDateTime {
+        self.comment_date
     }
 
-    fn as_html(&self) -> String {
-        self.as_string()
+    fn kind(&self) -> UpdateKind {
+        UpdateKind::Post
     }
 
-    fn timestamp(&self) -> NaiveDateTime {
-        self.comment_date
+    fn id(&self) -> String {
+        self.item_id.to_owned()
+    }
+
+    fn topic_id(&self) -> String {
+        self.post_title.to_owned()
+    }
+
+    fn content_hash(&self) -> String {
+        content_hash(&[&self.user_name, &self.post_title, &self.comment_text])
+    }
+}
+
+/// Hash contents of the update so we can notice when it's changed.
+///
+/// This is FNV-1a, as opposed to std hashers it's guaranteed to stay the same
+/// between Rust versions, so hashes saved in DB are still valid after upgrade.
+pub fn content_hash(parts: &[&str]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        // separator so ("ab", "c") and ("a", "bc") differ
+        for byte in part.bytes().chain(Some(0)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+/// Tags Matrix spec recommends clients to allow in `formatted_body`
+const ALLOWED_TAGS: &[&str] = &[
+    "font", "del", "h1", "h2", "h3", "h4", "h5", "h6", "blockquote", "p", "a", "ul", "ol", "sup", "sub", "li", "b",
+    "i", "u", "strong", "em", "strike", "code", "hr", "br", "div", "table", "thead", "tbody", "tr", "th", "td",
+    "caption", "pre", "span", "details", "summary",
+];
+
+/// Tags that are dropped along with their contents, their text is never meant to be shown
+const DROPPED_TAGS: &[&str] = &["script", "style", "iframe", "object", "embed", "svg", "math", "template"];
+
+/// Link schemes that are safe to click on
+const ALLOWED_SCHEMES: &[&str] = &["http://", "https://", "ftp://", "mailto:", "magnet:"];
+
+/// Rebuild HTML of the node contents leaving only tags and attributes from Matrix-recommended subset.
+///
+/// Downstream content is untrusted, so everything is rebuilt from scratch rather than filtered:
+/// text is escaped, unknown tags are replaced with their contents, attributes are only kept
+/// where they are harmless, e.g. links with safe schemes. Images are replaced with their alt text
+/// as Matrix only shows `mxc://` ones anyway. Relative links are made absolute with `base_url`.
+pub fn sanitize_html(node: &Node, base_url: &str) -> String {
+    let mut html = String::new();
+    for child in node.children() {
+        sanitize_node(&child, base_url, &mut html);
+    }
+    html
+}
+
+fn sanitize_node(node: &Node, base_url: &str, html: &mut String) {
+    if let Some(text) = node.as_text() {
+        html.push_str(&escape_html(text));
+        return;
+    }
+
+    let tag = match node.name() {
+        None => return, // comment
+        Some(name) => name.to_lowercase(),
+    };
+    if DROPPED_TAGS.contains(&tag.as_str()) {
+        return;
+    }
+    if tag == "img" {
+        html.push_str(&escape_html(node.attr("alt").unwrap_or_default()));
+        return;
+    }
+    if !ALLOWED_TAGS.contains(&tag.as_str()) {
+        html.push_str(&sanitize_html(node, base_url));
+        return;
+    }
+
+    html.push('<');
+    html.push_str(&tag);
+    for &(name, ref value) in &allowed_attrs(&tag, node, base_url) {
+        html.push_str(&format!(" {}=\"{}\"", name, escape_html(value)));
+    }
+    if tag == "br" || tag == "hr" {
+        html.push_str("/>");
+        return;
+    }
+    html.push('>');
+    html.push_str(&sanitize_html(node, base_url));
+    html.push_str(&format!("</{}>", tag));
+}
+
+/// Attributes of the node that can be kept for this tag
+fn allowed_attrs(tag: &str, node: &Node, base_url: &str) -> Vec<(&'static str, String)> {
+    let mut attrs = vec![];
+    match tag {
+        "a" => {
+            let href = node.attr("href").unwrap_or_default().trim();
+            let href = if href.starts_with('/') && !href.starts_with("//") {
+                base_url.trim_right_matches('/').to_owned() + href
+            } else {
+                href.to_owned()
+            };
+            let lowercase = href.to_lowercase();
+            if ALLOWED_SCHEMES.iter().any(|scheme| lowercase.starts_with(scheme)) {
+                attrs.push(("href", href));
+            }
+        }
+        "ol" => {
+            if let Some(start) = node.attr("start") {
+                if start.parse::<u32>().is_ok() {
+                    attrs.push(("start", start.to_owned()));
+                }
+            }
+        }
+        "code" => {
+            if let Some(class) = node.attr("class") {
+                if class.starts_with("language-") && !class.contains(' ') {
+                    attrs.push(("class", class.to_owned()));
+                }
+            }
+        }
+        _ => {}
+    }
+    attrs
+}
+
+#[cfg(test)]
+mod tests {
+    use select::document::Document;
+    use select::predicate::Attr;
+
+    use super::*;
+
+    fn sanitize(html: &str) -> String {
+        let doc = Document::from(format!("<div id=\"content\">{}</div>", html).as_str());
+        let content = doc.find(Attr("id", "content")).next().expect("Content must be parsed");
+        sanitize_html(&content, "https://www.linux.org.ru/")
+    }
+
+    #[test]
+    fn drops_scripts_and_frames() {
+        let html = "<p>before</p><script>alert(1)</script><iframe src=\"https://evil.com\">frame</iframe><p>after</p>";
+        assert_eq!(sanitize(html), "<p>before</p><p>after</p>");
+    }
+
+    #[test]
+    fn drops_unsafe_links() {
+        assert_eq!(sanitize("<a href=\"javascript:alert(1)\">x</a>"), "<a>x</a>");
+        assert_eq!(sanitize("<a href=\" JavaScript:alert(1)\">x</a>"), "<a>x</a>");
+        assert_eq!(sanitize("<a href=\"data:text/html;base64,PHNjcmlwdD4=\">x</a>"), "<a>x</a>");
+        assert_eq!(sanitize("<a href=\"https://example.com/?a=1&amp;b=2\">x</a>"),
+                   "<a href=\"https://example.com/?a=1&amp;b=2\">x</a>");
+    }
+
+    #[test]
+    fn drops_event_handlers() {
+        assert_eq!(sanitize("<b onclick=\"alert(1)\" onmouseover=\"alert(2)\">bold</b>"), "<b>bold</b>");
+        assert_eq!(sanitize("<a href=\"https://example.com\" onclick=\"alert(1)\">x</a>"),
+                   "<a href=\"https://example.com\">x</a>");
+    }
+
+    #[test]
+    fn keeps_text_of_unknown_tags() {
+        let html = "<custom><inner>deep <i>text</i></inner> &lt;tail&gt;</custom>";
+        assert_eq!(sanitize(html), "deep <i>text</i> &lt;tail&gt;");
+    }
+
+    #[test]
+    fn resolves_relative_links() {
+        assert_eq!(sanitize("<a href=\"/forum/talks/1\">t</a>"),
+                   "<a href=\"https://www.linux.org.ru/forum/talks/1\">t</a>");
+        // protocol-relative links go to other hosts, they have no safe scheme
+        assert_eq!(sanitize("<a href=\"//evil.com/x\">t</a>"), "<a>t</a>");
+    }
+
+    #[test]
+    fn replaces_images_with_alt_text() {
+        assert_eq!(sanitize("<p><img src=\"smile.png\" alt=\"&quot;smile&quot;\"> ok</p>"),
+                   "<p>&quot;smile&quot; ok</p>");
+        assert_eq!(sanitize("<p><img src=\"smile.png\">ok</p>"), "<p>ok</p>");
     }
 }
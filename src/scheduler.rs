@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use diesel::sqlite::SqliteConnection;
+
+use database;
+use entities::*;
+
+/// Downstream account, as polled by adapter
+pub type Account = (Adapter, String);
+
+/// What a poll tells about activity of the account
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activity {
+    /// polling failed, nothing is known
+    Unknown,
+    /// account page didn't change since the last poll
+    Unchanged,
+    /// timestamp of the most recent item account has, if it has any
+    LatestItem(Option<NaiveDateTime>),
+}
+
+/// Decides which downstream accounts are polled and when.
+///
+/// Each account has its own interval between polls that starts at adapter's minimum,
+/// halves when account posts something new and grows by half while it's dormant, within adapter limits.
+/// On top of that, requests to each adapter are limited by its budget per minute.
+/// Schedule is persisted, so restarts don't reset intervals.
+pub struct Scheduler {
+    schedules: HashMap<Account, PollSchedule>,
+    /// when requests were made to adapters during the last minute, with their cost
+    spent: HashMap<Adapter, Vec<(NaiveDateTime, usize)>>,
+}
+
+impl Scheduler {
+
+    pub fn new(schedules: Vec<PollSchedule>) -> Scheduler {
+        Scheduler {
+            schedules: schedules.into_iter().map(|s| ((s.adapter, s.linked_user_id.to_owned()), s)).collect(),
+            spent: HashMap::new(),
+        }
+    }
+
+    /// Pick accounts that are due to be polled now and fit into adapter budgets, most overdue first.
+    /// Accounts are supplied with flag whether replies should be polled too.
+    ///
+    /// Requests of picked accounts are counted against budgets right away.
+    pub fn pick_due(&mut self, accounts: HashMap<Account, bool>, now: NaiveDateTime) -> Vec<(Account, bool)> {
+        // accounts never polled before are due right away
+        let mut due: Vec<(NaiveDateTime, Account, bool)> = accounts.into_iter()
+            .map(|(account, with_replies)| {
+                let next_poll = self.schedules.get(&account).map_or(now, |s| s.next_poll);
+                (next_poll, account, with_replies)
+            })
+            .filter(|&(next_poll, _, _)| next_poll <= now)
+            .collect();
+        due.sort_by_key(|&(next_poll, _, _)| next_poll);
+
+        let mut picked = vec![];
+        for (_, account, with_replies) in due {
+            let adapter = account.0;
+            let cost = adapter.poll_cost(with_replies);
+            let spent = self.spent.entry(adapter).or_insert_with(Vec::new);
+            spent.retain(|&(spent_at, _)| spent_at > now - Duration::minutes(1));
+            let spent_total: usize = spent.iter().map(|&(_, cost)| cost).sum();
+            if spent_total + cost > adapter.limits().requests_per_minute {
+                // this one waits for the next cycle, and so do the ones behind it,
+                // otherwise cheap polls could keep the most overdue one waiting forever
+                break;
+            }
+
+            spent.push((now, cost));
+            picked.push((account, with_replies));
+        }
+        picked
+    }
+
+    /// Reschedule the account after it was polled, according to `activity` the poll has shown.
+    /// Accounts with links `pending` verification are polled as often as possible,
+    /// so users don't wait long for their proof to be noticed.
+    pub fn polled(&mut self, conn: &SqliteConnection, account: &Account, activity: Activity, pending: bool,
+                  now: NaiveDateTime) {
+        let limits = account.0.limits();
+        let schedule = self.schedules.entry(account.clone()).or_insert_with(|| {
+            PollSchedule {
+                id: 0,
+                adapter: account.0,
+                linked_user_id: account.1.to_owned(),
+                next_poll: now,
+                interval_secs: limits.min_interval_secs,
+                latest_item: None,
+            }
+        });
+        reschedule(schedule, limits, activity, pending, now);
+
+        if let Err(error) = database::save_schedule(conn, schedule) {
+            error!("Couldn't save polling schedule of {}: {:?}", account.1, error);
+        }
+    }
+
+    /// Poll the account as soon as budget allows, e.g. when a new link to it waits for verification.
+    /// Interval starts from adapter's minimum again, accounts that were never polled are due anyway
+    pub fn poll_soon(&mut self, conn: &SqliteConnection, account: &Account, now: NaiveDateTime) {
+        let schedule = match self.schedules.get_mut(account) {
+            None => return,
+            Some(schedule) => schedule,
+        };
+        schedule.interval_secs = account.0.limits().min_interval_secs;
+        schedule.next_poll = schedule.next_poll.min(now);
+
+        if let Err(error) = database::save_schedule(conn, schedule) {
+            error!("Couldn't save polling schedule of {}: {:?}", account.1, error);
+        }
+    }
+
+    /// Forget schedules of accounts that have no links anymore, so they don't pile up in DB
+    pub fn forget_unlinked(&mut self, conn: &SqliteConnection, requests: &[UserInfo]) {
+        let unlinked: Vec<Account> = self.schedules.keys()
+            .filter(|&&(adapter, ref user)| !requests.iter().any(|i| i.adapter == adapter && i.linked_user_id == *user))
+            .cloned()
+            .collect();
+        for account in unlinked {
+            if let Err(error) = database::delete_schedule(conn, &account) {
+                error!("Couldn't forget polling schedule of {}: {:?}", account.1, error);
+                continue;
+            }
+            self.schedules.remove(&account);
+        }
+    }
+}
+
+/// Change interval of the account after poll, see `Scheduler::polled`.
+/// Failed polls keep the interval, account may well be active while its source is down
+fn reschedule(schedule: &mut PollSchedule, limits: PollLimits, activity: Activity, pending: bool,
+              now: NaiveDateTime) {
+    let active = match (activity, schedule.latest_item) {
+        (Activity::LatestItem(Some(latest)), Some(known)) => Some(latest > known),
+        (Activity::Unknown, _) => None,
+        _ => Some(false),
+    };
+    schedule.interval_secs = match (pending, active) {
+        (true, _) => limits.min_interval_secs,
+        (false, None) => schedule.interval_secs,
+        (false, Some(true)) => schedule.interval_secs / 2,
+        (false, Some(false)) => schedule.interval_secs * 3 / 2,
+    };
+    schedule.interval_secs = schedule.interval_secs.max(limits.min_interval_secs).min(limits.max_interval_secs);
+    schedule.next_poll = now + Duration::seconds(i64::from(schedule.interval_secs));
+    if let Activity::LatestItem(Some(latest)) = activity {
+        schedule.latest_item = Some(latest);
+    }
+}
+
+#[cfg(all(test, feature = "linux-org-ru"))]
+mod tests {
+    use super::*;
+
+    const LIMITS: PollLimits = PollLimits {
+        min_interval_secs: 60,
+        max_interval_secs: 3600,
+        requests_per_minute: 12,
+    };
+
+    fn at(hour: u32, min: u32, sec: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2017, 11, 25).and_hms(hour, min, sec)
+    }
+
+    fn schedule(user: &str, next_poll: NaiveDateTime, interval_secs: i32) -> PollSchedule {
+        PollSchedule {
+            id: 0,
+            adapter: Adapter::LinuxOrgRu,
+            linked_user_id: user.to_owned(),
+            next_poll: next_poll,
+            interval_secs: interval_secs,
+            latest_item: Some(at(10, 0, 0)),
+        }
+    }
+
+    fn account(user: &str) -> Account {
+        (Adapter::LinuxOrgRu, user.to_owned())
+    }
+
+    #[test]
+    fn interval_halves_for_active_accounts() {
+        let mut polled = schedule("user", at(12, 0, 0), 800);
+        reschedule(&mut polled, LIMITS, Activity::LatestItem(Some(at(11, 0, 0))), false, at(12, 0, 0));
+        assert_eq!(polled.interval_secs, 400);
+        assert_eq!(polled.next_poll, at(12, 6, 40));
+        assert_eq!(polled.latest_item, Some(at(11, 0, 0)));
+
+        // but not below the minimum
+        let mut polled = schedule("user", at(12, 0, 0), 100);
+        reschedule(&mut polled, LIMITS, Activity::LatestItem(Some(at(11, 0, 0))), false, at(12, 0, 0));
+        assert_eq!(polled.interval_secs, 60);
+    }
+
+    #[test]
+    fn interval_grows_for_dormant_accounts() {
+        let mut polled = schedule("user", at(12, 0, 0), 800);
+        reschedule(&mut polled, LIMITS, Activity::LatestItem(Some(at(10, 0, 0))), false, at(12, 0, 0));
+        assert_eq!(polled.interval_secs, 1200);
+        assert_eq!(polled.next_poll, at(12, 20, 0));
+
+        // unchanged page has nothing new either, latest item is kept
+        reschedule(&mut polled, LIMITS, Activity::Unchanged, false, at(12, 20, 0));
+        assert_eq!(polled.interval_secs, 1800);
+        assert_eq!(polled.latest_item, Some(at(10, 0, 0)));
+
+        // but not above the maximum
+        reschedule(&mut polled, LIMITS, Activity::LatestItem(None), false, at(12, 50, 0));
+        assert_eq!(polled.interval_secs, 2700);
+        reschedule(&mut polled, LIMITS, Activity::Unchanged, false, at(13, 35, 0));
+        assert_eq!(polled.interval_secs, 3600);
+    }
+
+    #[test]
+    fn interval_is_kept_when_poll_fails() {
+        let mut polled = schedule("user", at(12, 0, 0), 800);
+        reschedule(&mut polled, LIMITS, Activity::Unknown, false, at(12, 0, 0));
+        assert_eq!(polled.interval_secs, 800);
+        assert_eq!(polled.next_poll, at(12, 13, 20));
+        assert_eq!(polled.latest_item, Some(at(10, 0, 0)));
+
+        // links waiting for verification still get polled often
+        reschedule(&mut polled, LIMITS, Activity::Unknown, true, at(12, 13, 20));
+        assert_eq!(polled.interval_secs, 60);
+    }
+
+    #[test]
+    fn pending_accounts_are_polled_often() {
+        let mut polled = schedule("user", at(12, 0, 0), 3600);
+        reschedule(&mut polled, LIMITS, Activity::LatestItem(Some(at(10, 0, 0))), true, at(12, 0, 0));
+        assert_eq!(polled.interval_secs, 60);
+        assert_eq!(polled.next_poll, at(12, 1, 0));
+    }
+
+    #[test]
+    fn only_due_accounts_are_picked_most_overdue_first() {
+        let mut scheduler = Scheduler::new(vec![
+            schedule("later", at(12, 5, 0), 60),
+            schedule("overdue", at(11, 0, 0), 60),
+            schedule("due", at(11, 59, 0), 60),
+        ]);
+        let accounts = vec![account("later"), account("overdue"), account("due"), account("new")]
+            .into_iter()
+            .map(|account| (account, false))
+            .collect();
+
+        let picked: Vec<String> = scheduler.pick_due(accounts, at(12, 0, 0)).into_iter().map(|(a, _)| a.1).collect();
+        assert_eq!(picked, vec!["overdue", "due", "new"]);
+    }
+
+    #[test]
+    fn new_links_dont_wait_for_dormant_schedule() {
+        let mut scheduler = Scheduler::new(vec![schedule("dormant", at(12, 50, 0), 3600)]);
+        let accounts = || -> HashMap<Account, bool> { vec![(account("dormant"), false)].into_iter().collect() };
+        assert!(scheduler.pick_due(accounts(), at(12, 0, 0)).is_empty());
+
+        let conn = database::test_connection();
+        scheduler.poll_soon(&conn, &account("dormant"), at(12, 0, 0));
+        assert_eq!(scheduler.pick_due(accounts(), at(12, 0, 0)), vec![(account("dormant"), false)]);
+        assert_eq!(scheduler.schedules[&account("dormant")].interval_secs, 60);
+    }
+
+    #[test]
+    fn budget_is_per_minute() {
+        let mut scheduler = Scheduler::new(vec![]);
+        let accounts = |count: usize| -> HashMap<Account, bool> {
+            (0..count).map(|idx| (account(&format!("user{}", idx)), false)).collect()
+        };
+
+        assert_eq!(scheduler.pick_due(accounts(20), at(12, 0, 0)).len(), 12);
+        assert_eq!(scheduler.pick_due(accounts(20), at(12, 0, 30)).len(), 0);
+        // requests made at 12:00:00 are out of the window a minute later
+        assert_eq!(scheduler.pick_due(accounts(20), at(12, 1, 1)).len(), 12);
+    }
+
+    #[test]
+    fn budget_keeps_overdue_accounts_first() {
+        let mut scheduler = Scheduler::new(vec![
+            schedule("overdue", at(11, 0, 0), 60),
+            schedule("due", at(11, 59, 0), 60),
+        ]);
+        let mut accounts: HashMap<Account, bool> = (0..8)
+            .map(|idx| (account(&format!("user{}", idx)), false))
+            .collect();
+        assert_eq!(scheduler.pick_due(accounts, at(11, 59, 30)).len(), 8);
+
+        // 4 requests are left in the budget: overdue one with replies costs more, so it waits
+        // and the cheap one behind it doesn't get ahead
+        accounts = vec![(account("overdue"), true), (account("due"), false)].into_iter().collect();
+        assert!(scheduler.pick_due(accounts.clone(), at(12, 0, 0)).is_empty());
+        let picked = scheduler.pick_due(accounts, at(12, 0, 31));
+        assert_eq!(picked, vec![(account("overdue"), true), (account("due"), false)]);
+    }
+}
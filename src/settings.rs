@@ -0,0 +1,405 @@
+use std::result;
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+
+use entities::*;
+use templates;
+
+/// Checks that value is acceptable for the setting, explains what's wrong otherwise
+type SettingValidator = fn(&str) -> result::Result<(), String>;
+
+/// Setting that can be changed per chat
+pub struct SettingSpec {
+    pub name: &'static str,
+    /// value used when setting was never changed in the chat
+    pub default: &'static str,
+    pub description: &'static str,
+    validate: SettingValidator,
+}
+
+/// All settings chats can change with `settings` command
+pub static SETTINGS: &[SettingSpec] = &[
+    SettingSpec {
+        name: "format",
+        default: "html",
+        description: "how updates are formatted: html or plain",
+        validate: validate_format,
+    },
+    SettingSpec {
+        name: "language",
+        default: "en",
+        description: "language of update messages: en or ru",
+        validate: validate_language,
+    },
+    SettingSpec {
+        name: "quiet_hours",
+        default: "",
+        description: "time range when updates are held back, e.g. 23:00-08:00, empty to disable",
+        validate: validate_quiet_hours,
+    },
+    SettingSpec {
+        name: "timezone",
+        default: "+00:00",
+        description: "time zone quiet hours are in, e.g. Europe/Moscow, or UTC offset like +03:00 \
+                      which doesn't follow daylight saving time",
+        validate: validate_timezone,
+    },
+    SettingSpec {
+        name: "rate_limit",
+        default: "0",
+        description: "maximum updates per minute, the rest are sent as one summary later, 0 for unlimited",
+        validate: validate_number,
+    },
+    SettingSpec {
+        name: "max_length",
+        default: "2000",
+        description: "maximum length of update message, longer ones are cut, 0 for unlimited",
+        validate: validate_number,
+    },
+    SettingSpec {
+        name: "digest",
+        default: "off",
+        description: "send updates as periodic summaries instead of one by one: off, hourly or daily",
+        validate: validate_digest,
+    },
+    SettingSpec {
+        name: "adapters",
+        default: "",
+        description: "comma-separated list of adapters that can be linked here, empty to allow all",
+        validate: validate_adapters,
+    },
+    SettingSpec {
+        name: "template_post_plain",
+        default: "",
+        description: "template for comments in plain format, e.g. '{author}: {text}', empty for built-in one",
+        validate: validate_template,
+    },
+    SettingSpec {
+        name: "template_post_html",
+        default: "",
+        description: "template for comments in html format, e.g. '{author}: {text}', empty for built-in one",
+        validate: validate_template,
+    },
+    SettingSpec {
+        name: "template_post_markdown",
+        default: "",
+        description: "template for comments in markdown format, e.g. '{author}: {text}', empty for built-in one",
+        validate: validate_template,
+    },
+    SettingSpec {
+        name: "template_reply_plain",
+        default: "",
+        description: "template for replies in plain format, e.g. '{author}: {text}', empty for built-in one",
+        validate: validate_template,
+    },
+    SettingSpec {
+        name: "template_reply_html",
+        default: "",
+        description: "template for replies in html format, e.g. '{author}: {text}', empty for built-in one",
+        validate: validate_template,
+    },
+    SettingSpec {
+        name: "template_reply_markdown",
+        default: "",
+        description: "template for replies in markdown format, e.g. '{author}: {text}', empty for built-in one",
+        validate: validate_template,
+    },
+];
+
+/// How updates should be formatted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+    /// Rich text with links
+    Html,
+    /// Just text
+    Plain,
+}
+
+/// Language of update messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    English,
+    Russian,
+}
+
+/// Time zone of the chat
+#[derive(Debug, Clone, Copy)]
+pub enum ChatZone {
+    /// zone from IANA database, follows its daylight saving time changes
+    Named(Tz),
+    /// fixed offset from UTC
+    Offset(FixedOffset),
+}
+
+impl ChatZone {
+
+    /// Time of the day in this zone
+    pub fn local_time(&self, now: DateTime<Utc>) -> NaiveTime {
+        match *self {
+            ChatZone::Named(zone) => now.with_timezone(&zone).time(),
+            ChatZone::Offset(offset) => now.with_timezone(&offset).time(),
+        }
+    }
+}
+
+/// Settings of one chat, combined from saved values and defaults
+#[derive(Debug, Clone, Default)]
+pub struct RoomSettings {
+    /// values that were changed in the chat, by setting name
+    values: HashMap<String, String>,
+}
+
+impl RoomSettings {
+
+    pub fn new(values: HashMap<String, String>) -> RoomSettings {
+        RoomSettings { values: values }
+    }
+
+    /// Current value of the setting, default if it was never changed
+    pub fn get(&self, name: &str) -> &str {
+        if let Some(value) = self.values.get(name) {
+            return value;
+        }
+        find_setting(name).map(|spec| spec.default).unwrap_or_default()
+    }
+
+    pub fn format(&self) -> MessageFormat {
+        match self.get("format") {
+            "plain" => MessageFormat::Plain,
+            _ => MessageFormat::Html,
+        }
+    }
+
+    pub fn language(&self) -> Language {
+        match self.get("language") {
+            "ru" => Language::Russian,
+            _ => Language::English,
+        }
+    }
+
+    /// Maximum length of message in chars, `None` if unlimited
+    pub fn max_length(&self) -> Option<usize> {
+        match self.get("max_length").parse::<usize>() {
+            Ok(0) | Err(_) => None,
+            Ok(length) => Some(length),
+        }
+    }
+
+    /// How long updates are held back before they're sent as digest, `None` if they're sent right away
+    pub fn digest_period(&self) -> Option<Duration> {
+        match self.get("digest") {
+            "hourly" => Some(Duration::hours(1)),
+            "daily" => Some(Duration::days(1)),
+            _ => None,
+        }
+    }
+
+    /// Whether links to this adapter are allowed in the chat
+    pub fn adapter_allowed(&self, adapter: Adapter) -> bool {
+        let allowed = self.get("adapters");
+        if allowed.trim().is_empty() {
+            return true;
+        }
+        allowed.split(',').any(|a| a.trim() == adapter.to_string())
+    }
+
+    /// Time zone of the chat, UTC if it's not set
+    pub fn timezone(&self) -> ChatZone {
+        parse_timezone(self.get("timezone")).unwrap_or_else(|| ChatZone::Offset(FixedOffset::east(0)))
+    }
+
+    /// Maximum number of messages per minute, `None` if unlimited
+    pub fn rate_limit(&self) -> Option<usize> {
+        match self.get("rate_limit").parse::<usize>() {
+            Ok(0) | Err(_) => None,
+            Ok(limit) => Some(limit),
+        }
+    }
+
+    /// Whether updates should be held back at this time, quiet hours are in chat's time zone
+    pub fn in_quiet_hours(&self, now: DateTime<Utc>) -> bool {
+        let local = self.timezone().local_time(now);
+        match parse_time_range(self.get("quiet_hours")) {
+            None => false,
+            // range may span midnight, e.g. 23:00-08:00
+            Some((start, end)) if start <= end => start <= local && local < end,
+            Some((start, end)) => local >= start || local < end,
+        }
+    }
+}
+
+/// Find setting by name
+pub fn find_setting(name: &str) -> Option<&'static SettingSpec> {
+    SETTINGS.iter().find(|spec| spec.name == name)
+}
+
+/// Check the value for the setting, explains what's wrong with it if it's not acceptable
+pub fn validate(name: &str, value: &str) -> result::Result<(), String> {
+    match find_setting(name) {
+        None => Err(format!("Unknown setting '{}'", name)),
+        Some(spec) => (spec.validate)(value),
+    }
+}
+
+/// Describe all settings of the chat or the requested one
+pub fn describe(settings: &RoomSettings, name: Option<&str>) -> String {
+    match name {
+        Some(name) => {
+            match find_setting(name) {
+                None => format!("Unknown setting '{}'", name),
+                Some(spec) => format!("{} = '{}'\n{}, default is '{}'",
+                                      spec.name, settings.get(spec.name), spec.description, spec.default),
+            }
+        }
+        None => {
+            let lines: Vec<String> = SETTINGS.iter()
+                .map(|spec| format!("{} = '{}'", spec.name, settings.get(spec.name)))
+                .collect();
+            format!("Settings of this chat:\n{}", lines.join("\n"))
+        }
+    }
+}
+
+/// Parse time range like `23:00-08:00`
+fn parse_time_range(range: &str) -> Option<(NaiveTime, NaiveTime)> {
+    let mut bounds = range.trim().splitn(2, '-').map(|b| NaiveTime::parse_from_str(b.trim(), "%H:%M").ok());
+    match (bounds.next(), bounds.next()) {
+        (Some(Some(start)), Some(Some(end))) => Some((start, end)),
+        _ => None,
+    }
+}
+
+/// Parse time zone name like `Europe/Moscow` or UTC offset
+fn parse_timezone(zone: &str) -> Option<ChatZone> {
+    if let Some(offset) = parse_offset(zone) {
+        return Some(ChatZone::Offset(offset));
+    }
+    zone.trim().parse::<Tz>().ok().map(ChatZone::Named)
+}
+
+/// Parse UTC offset like `+03:00` or `-05:30`
+fn parse_offset(offset: &str) -> Option<FixedOffset> {
+    let offset = offset.trim();
+    let (sign, rest) = match offset.chars().next() {
+        Some('+') => (1, &offset[1..]),
+        Some('-') => (-1, &offset[1..]),
+        _ => return None,
+    };
+    let time = match NaiveTime::parse_from_str(rest, "%H:%M") {
+        Ok(time) => time,
+        Err(_) => return None,
+    };
+    FixedOffset::east_opt(sign * (time.hour() * 3600 + time.minute() * 60) as i32)
+}
+
+fn validate_format(value: &str) -> result::Result<(), String> {
+    match value {
+        "html" | "plain" => Ok(()),
+        _ => Err("Format must be either html or plain".to_owned()),
+    }
+}
+
+fn validate_language(value: &str) -> result::Result<(), String> {
+    match value {
+        "en" | "ru" => Ok(()),
+        _ => Err("Language must be either en or ru".to_owned()),
+    }
+}
+
+fn validate_quiet_hours(value: &str) -> result::Result<(), String> {
+    if value.is_empty() || parse_time_range(value).is_some() {
+        return Ok(());
+    }
+    Err("Quiet hours must be a time range like 23:00-08:00".to_owned())
+}
+
+fn validate_timezone(value: &str) -> result::Result<(), String> {
+    match parse_timezone(value) {
+        Some(_) => Ok(()),
+        None => Err("Time zone must be a name like Europe/Moscow or UTC offset like +03:00".to_owned()),
+    }
+}
+
+fn validate_number(value: &str) -> result::Result<(), String> {
+    value.parse::<usize>().map(|_| ()).map_err(|_| format!("Number expected, got '{}'", value))
+}
+
+fn validate_template(value: &str) -> result::Result<(), String> {
+    templates::validate(value)
+}
+
+fn validate_digest(value: &str) -> result::Result<(), String> {
+    match value {
+        "off" | "hourly" | "daily" => Ok(()),
+        _ => Err("Digest must be one of off, hourly or daily".to_owned()),
+    }
+}
+
+fn validate_adapters(value: &str) -> result::Result<(), String> {
+    for name in value.split(',').map(|a| a.trim()).filter(|a| !a.is_empty()) {
+        if str::parse::<Adapter>(name).is_err() {
+            return Err(format!("Unknown adapter '{}'", name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quiet_hours(timezone: &str) -> RoomSettings {
+        let mut values = HashMap::new();
+        values.insert("quiet_hours".to_owned(), "23:00-07:00".to_owned());
+        values.insert("timezone".to_owned(), timezone.to_owned());
+        RoomSettings::new(values)
+    }
+
+    /// Check 23:00-07:00 quiet hours starting at the night of `date` in `zone`
+    fn check_night<Z: TimeZone>(settings: &RoomSettings, zone: &Z, date: NaiveDate) {
+        let quiet = |date: NaiveDate, hour, min| {
+            let local = zone.from_local_datetime(&date.and_hms(hour, min, 0)).unwrap();
+            settings.in_quiet_hours(local.with_timezone(&Utc))
+        };
+        assert!(!quiet(date, 22, 59));
+        assert!(quiet(date, 23, 0));
+        assert!(quiet(date.succ(), 6, 59));
+        assert!(!quiet(date.succ(), 7, 0));
+    }
+
+    #[test]
+    fn quiet_hours_in_fixed_offset() {
+        let settings = quiet_hours("+03:00");
+        let zone = FixedOffset::east(3 * 3600);
+        check_night(&settings, &zone, NaiveDate::from_ymd(2018, 1, 15));
+        check_night(&settings, &zone, NaiveDate::from_ymd(2018, 7, 15));
+    }
+
+    #[test]
+    fn quiet_hours_in_named_zone() {
+        // both standard and daylight saving time
+        let settings = quiet_hours("America/New_York");
+        check_night(&settings, &Tz::America__New_York, NaiveDate::from_ymd(2018, 1, 15));
+        check_night(&settings, &Tz::America__New_York, NaiveDate::from_ymd(2018, 7, 15));
+    }
+
+    #[test]
+    fn fixed_offset_ignores_daylight_saving_time() {
+        let settings = quiet_hours("-05:00");
+        check_night(&settings, &FixedOffset::west(5 * 3600), NaiveDate::from_ymd(2018, 7, 15));
+
+        // it's an hour earlier than in New York in summer
+        let new_york = |hour, min| Tz::America__New_York.ymd(2018, 7, 15).and_hms(hour, min, 0).with_timezone(&Utc);
+        assert!(!settings.in_quiet_hours(new_york(23, 30)));
+        assert!(settings.in_quiet_hours(new_york(7, 30)));
+    }
+
+    #[test]
+    fn no_quiet_hours() {
+        let settings = RoomSettings::default();
+        assert!(!settings.in_quiet_hours(Utc.ymd(2018, 1, 15).and_hms(3, 0, 0)));
+    }
+}
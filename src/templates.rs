@@ -0,0 +1,393 @@
+use std::result;
+
+use entities::*;
+use settings::{Language, RoomSettings};
+
+/// Fields of the update templates can refer to as `{name}`
+pub static FIELDS: &[&str] = &["author", "author_url", "title", "url", "text", "date", "section"];
+
+/// Fields holding links, some dialects escape them differently from text
+const URL_FIELDS: &[&str] = &["author_url", "url"];
+
+/// IRC formatting control codes: bold, color, reset, reverse, italic, underline
+const IRC_CONTROL_CODES: &[char] = &['\x02', '\x03', '\x0f', '\x16', '\x1d', '\x1f'];
+
+/// Format of the message template renders to, determines how field values are escaped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TemplateFormat {
+    Plain,
+    Html,
+    Markdown(MarkdownType),
+}
+
+impl TemplateFormat {
+
+    /// Make field value safe to insert into the message of this format
+    fn escape(&self, field: &str, value: &str) -> String {
+        let is_url = URL_FIELDS.contains(&field);
+        match *self {
+            TemplateFormat::Plain => value.to_owned(),
+            TemplateFormat::Html => escape_html(value),
+            TemplateFormat::Markdown(dialect) => escape_markdown(dialect, value, is_url),
+        }
+    }
+
+    /// Link to `url` titled `text`, both are template snippets
+    fn link(&self, text: &str, url: &str) -> String {
+        match *self {
+            TemplateFormat::Plain => text.to_owned(),
+            TemplateFormat::Html => format!("<a href='{}'>{}</a>", url, text),
+            TemplateFormat::Markdown(MarkdownType::Slack) => format!("<{}|{}>", url, text),
+            TemplateFormat::Markdown(MarkdownType::Irc) => format!("\x02{}\x02 ({})", text, url),
+            TemplateFormat::Markdown(_) => format!("[{}]({})", text, url),
+        }
+    }
+
+    /// Separates headline of the update from its text
+    fn text_separator(&self) -> &'static str {
+        match *self {
+            TemplateFormat::Html => "<br/>",
+            TemplateFormat::Markdown(MarkdownType::Irc) => " ",
+            _ => "\n\t",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            TemplateFormat::Plain => "plain",
+            TemplateFormat::Html => "html",
+            TemplateFormat::Markdown(_) => "markdown",
+        }
+    }
+}
+
+/// Render the update using template chat configured for its kind and format, or built-in one
+pub fn render_update(update: &UpdateDesc, settings: &RoomSettings, format: TemplateFormat) -> String {
+    let setting = template_setting(update.kind(), format);
+    let template = match settings.get(&setting) {
+        "" => default_template(update.kind(), format, settings.language()),
+        custom => custom.to_owned(),
+    };
+    render(&template, &update.fields(), format)
+}
+
+/// Name of the chat setting holding template for updates of this kind in this format,
+/// e.g. `template_post_html`. All markdown dialects share the same setting
+pub fn template_setting(kind: UpdateKind, format: TemplateFormat) -> String {
+    format!("template_{}_{}", kind.to_string().to_lowercase(), format.name())
+}
+
+/// Template used when chat didn't configure its own
+pub fn default_template(kind: UpdateKind, format: TemplateFormat, language: Language) -> String {
+    let author = format.link("{author}", "{author_url}");
+    let title = format.link("{title}", "{url}");
+    let text = match format {
+        TemplateFormat::Plain => "'{text}'",
+        _ => "{text}",
+    };
+    format!("{{date}}: {}:{}{}", headline(kind, language, &author, &title), format.text_separator(), text)
+}
+
+/// Describe what happened, e.g. `author added comment to post title`
+fn headline(kind: UpdateKind, language: Language, author: &str, title: &str) -> String {
+    match (kind, language) {
+        (UpdateKind::Post, Language::English) => format!("{} added comment to post {}", author, title),
+        (UpdateKind::Reply, Language::English) => format!("{} replied to you in {}", author, title),
+        (UpdateKind::Post, Language::Russian) => format!("новый комментарий от {} в теме {}", author, title),
+        (UpdateKind::Reply, Language::Russian) => format!("ответ от {} в теме {}", author, title),
+    }
+}
+
+/// Substitute `{field}` placeholders with escaped field values, `{{` and `}}` stand for literal braces.
+/// In HTML format `{text}` is substituted with sanitized rich text if update has it.
+///
+/// Template is expected to be validated, unknown fields are left as is.
+pub fn render(template: &str, fields: &UpdateFields, format: TemplateFormat) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                match (format, name.as_str(), &fields.rich_text) {
+                    // rich text is sanitized by adapter, escaping it would show the markup
+                    (TemplateFormat::Html, "text", &Some(ref html)) => result.push_str(html),
+                    _ => match fields.get(&name) {
+                        Some(value) => result.push_str(&format.escape(&name, &value)),
+                        None => result.push_str(&format!("{{{}}}", name)),
+                    },
+                }
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+/// Check that template only refers to known fields and has no unbalanced braces
+pub fn validate(template: &str) -> result::Result<(), String> {
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+            }
+            '{' => {
+                let mut name = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        closed = true;
+                        break;
+                    }
+                    name.push(c);
+                }
+                if !closed {
+                    return Err("Unclosed '{' in template, use '{{' for literal brace".to_owned());
+                }
+                if !FIELDS.contains(&name.as_str()) {
+                    return Err(format!("Unknown field '{}', available fields are: {}", name, FIELDS.join(", ")));
+                }
+            }
+            '}' => return Err("Unmatched '}' in template, use '}}' for literal brace".to_owned()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Escape text so it's shown as is inside HTML element or quoted attribute
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escape characters that have special meaning in the markdown dialect.
+/// Links only need escaping of characters that would end them early
+fn escape_markdown(dialect: MarkdownType, text: &str, is_url: bool) -> String {
+    let special: &[char] = match (dialect, is_url) {
+        (MarkdownType::Slack, true) => {
+            // entities aren't decoded in links, so characters that would end the link are percent-encoded
+            return text.replace('&', "&amp;").replace('<', "%3C").replace('>', "%3E").replace('|', "%7C");
+        }
+        (MarkdownType::Slack, false) => {
+            // Slack only needs control characters escaped as entities
+            return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+        }
+        (MarkdownType::Irc, _) => return text.chars().filter(|c| !IRC_CONTROL_CODES.contains(c)).collect(),
+        (_, true) => &['\\', ')'],
+        (MarkdownType::Telegram, false) => &[
+            '\\', '_', '*', '[', ']', '(', ')', '~', '`', '>', '#', '+', '-', '=', '|', '{', '}', '.', '!',
+        ],
+        (MarkdownType::Discord, false) => &['\\', '*', '_', '~', '`', '|', '>', '[', ']', '(', ')'],
+        (MarkdownType::GitHub, false) | (MarkdownType::Matrix, false) => &[
+            '\\', '*', '_', '`', '[', ']', '(', ')', '#', '<', '>',
+        ],
+    };
+
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+
+    struct Comment {
+        kind: UpdateKind,
+    }
+
+    impl UpdateDesc for Comment {
+        fn fields(&self) -> UpdateFields {
+            fields()
+        }
+
+        fn timestamp(&self) -> NaiveDateTime {
+            NaiveDateTime::from_timestamp(0, 0)
+        }
+
+        fn kind(&self) -> UpdateKind {
+            self.kind
+        }
+
+        fn id(&self) -> String {
+            "1".to_owned()
+        }
+
+        fn topic_id(&self) -> String {
+            "topic".to_owned()
+        }
+
+        fn content_hash(&self) -> String {
+            String::new()
+        }
+    }
+
+    fn fields() -> UpdateFields {
+        UpdateFields {
+            author: "bob".to_owned(),
+            author_url: "https://www.linux.org.ru/people/bob/profile".to_owned(),
+            title: "Rust <3".to_owned(),
+            url: "https://www.linux.org.ru/forum/talks/1?cid=2".to_owned(),
+            text: "hi & bye".to_owned(),
+            rich_text: None,
+            date: "2017-11-25 12:00".to_owned(),
+            section: "talks".to_owned(),
+        }
+    }
+
+    fn settings(values: &[(&str, &str)]) -> RoomSettings {
+        RoomSettings::new(values.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect())
+    }
+
+    #[test]
+    fn render_substitutes_fields() {
+        assert_eq!(render("{author} in {section}: {text}", &fields(), TemplateFormat::Plain), "bob in talks: hi & bye");
+        assert_eq!(render("{{{author}}} {{text}}", &fields(), TemplateFormat::Plain), "{bob} {text}");
+        assert_eq!(render("{title}: {text}", &fields(), TemplateFormat::Html), "Rust &lt;3: hi &amp; bye");
+
+        // sanitized rich text is used as is in HTML only
+        let rich = UpdateFields { rich_text: Some("<b>hi</b> &amp; bye".to_owned()), ..fields() };
+        assert_eq!(render("{text}", &rich, TemplateFormat::Html), "<b>hi</b> &amp; bye");
+        assert_eq!(render("{text}", &rich, TemplateFormat::Plain), "hi & bye");
+    }
+
+    #[test]
+    fn render_keeps_unknown_fields() {
+        assert_eq!(render("{author} {unknown}", &fields(), TemplateFormat::Plain), "bob {unknown}");
+    }
+
+    #[test]
+    fn validate_rejects_unknown_fields() {
+        assert_eq!(validate("{author}: {text} {{literal}}"), Ok(()));
+        assert_eq!(validate("{author}: {unknown}"),
+                   Err("Unknown field 'unknown', available fields are: \
+                        author, author_url, title, url, text, date, section".to_owned()));
+        assert!(validate("{author").unwrap_err().starts_with("Unclosed '{'"));
+        assert!(validate("author}").unwrap_err().starts_with("Unmatched '}'"));
+    }
+
+    #[test]
+    fn default_templates_depend_on_language() {
+        assert_eq!(default_template(UpdateKind::Post, TemplateFormat::Plain, Language::English),
+                   "{date}: {author} added comment to post {title}:\n\t'{text}'");
+        assert_eq!(default_template(UpdateKind::Reply, TemplateFormat::Html, Language::Russian),
+                   "{date}: ответ от <a href='{author_url}'>{author}</a> \
+                    в теме <a href='{url}'>{title}</a>:<br/>{text}");
+
+        let formats = [TemplateFormat::Plain, TemplateFormat::Html, TemplateFormat::Markdown(MarkdownType::Slack),
+                       TemplateFormat::Markdown(MarkdownType::Irc), TemplateFormat::Markdown(MarkdownType::Matrix)];
+        for &format in &formats {
+            for &language in &[Language::English, Language::Russian] {
+                for &kind in &[UpdateKind::Post, UpdateKind::Reply] {
+                    assert_eq!(validate(&default_template(kind, format, language)), Ok(()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn render_update_falls_back_to_default_template() {
+        let post = Comment { kind: UpdateKind::Post };
+        let reply = Comment { kind: UpdateKind::Reply };
+        let russian = settings(&[("language", "ru")]);
+        assert_eq!(render_update(&post, &russian, TemplateFormat::Plain),
+                   "2017-11-25 12:00: новый комментарий от bob в теме Rust <3:\n\t'hi & bye'");
+
+        // template is set per kind and format
+        let custom = settings(&[("template_post_plain", "{author}: {text}")]);
+        assert_eq!(render_update(&post, &custom, TemplateFormat::Plain), "bob: hi & bye");
+        assert_eq!(render_update(&reply, &custom, TemplateFormat::Plain),
+                   "2017-11-25 12:00: bob replied to you in Rust <3:\n\t'hi & bye'");
+        assert!(render_update(&post, &custom, TemplateFormat::Html).contains(" added comment to post "));
+    }
+
+    fn escape(dialect: MarkdownType, text: &str) -> String {
+        escape_markdown(dialect, text, false)
+    }
+
+    fn escape_url(dialect: MarkdownType, url: &str) -> String {
+        escape_markdown(dialect, url, true)
+    }
+
+    #[test]
+    fn slack_escapes_entities() {
+        assert_eq!(escape(MarkdownType::Slack, "<b>fish & chips</b> *bold* _it_"),
+                   "&lt;b&gt;fish &amp; chips&lt;/b&gt; *bold* _it_");
+        assert_eq!(escape(MarkdownType::Slack, "<!channel> <@U123>"), "&lt;!channel&gt; &lt;@U123&gt;");
+    }
+
+    #[test]
+    fn slack_links_cant_be_broken_out_of() {
+        let url = "https://example.com/?a=1&b=|x><!channel>";
+        assert_eq!(escape_url(MarkdownType::Slack, url), "https://example.com/?a=1&amp;b=%7Cx%3E%3C!channel%3E");
+
+        let link = TemplateFormat::Markdown(MarkdownType::Slack).link("{title}", "{url}");
+        let fields = UpdateFields { title: "a|b>".to_owned(), url: url.to_owned(), ..Default::default() };
+        assert_eq!(render(&link, &fields, TemplateFormat::Markdown(MarkdownType::Slack)),
+                   "<https://example.com/?a=1&amp;b=%7Cx%3E%3C!channel%3E|a|b&gt;>");
+    }
+
+    #[test]
+    fn irc_strips_control_codes() {
+        assert_eq!(escape(MarkdownType::Irc, "\x02bold\x02 \x0304red\x0f \x16rev\x16 \x1dit\x1d \x1fu\x1f"),
+                   "bold 04red rev it u");
+        assert_eq!(escape_url(MarkdownType::Irc, "https://example.com/\x03x"), "https://example.com/x");
+        // nothing else is special in IRC
+        assert_eq!(escape(MarkdownType::Irc, "*[a](b)* <c>"), "*[a](b)* <c>");
+    }
+
+    #[test]
+    fn markdown_escapes_special_characters() {
+        assert_eq!(escape(MarkdownType::GitHub, "*a* _b_ [c](d) `e` # <f>"),
+                   "\\*a\\* \\_b\\_ \\[c\\]\\(d\\) \\`e\\` \\# \\<f\\>");
+        assert_eq!(escape(MarkdownType::Matrix, "1 * 2"), "1 \\* 2");
+        assert_eq!(escape(MarkdownType::Telegram, "v1.0-rc! (beta)"), "v1\\.0\\-rc\\! \\(beta\\)");
+        assert_eq!(escape(MarkdownType::Discord, "||spoiler|| ~~no~~ > quote"),
+                   "\\|\\|spoiler\\|\\| \\~\\~no\\~\\~ \\> quote");
+    }
+
+    #[test]
+    fn markdown_links_cant_be_broken_out_of() {
+        let url = "https://example.com/wiki/A_(b)\\";
+        for dialect in &[MarkdownType::GitHub, MarkdownType::Matrix, MarkdownType::Telegram, MarkdownType::Discord] {
+            assert_eq!(escape_url(*dialect, url), "https://example.com/wiki/A_(b\\)\\\\");
+
+            let format = TemplateFormat::Markdown(*dialect);
+            let title = "[x](javascript:y)".to_owned();
+            let fields = UpdateFields { title: title, url: url.to_owned(), ..Default::default() };
+            let rendered = render(&format.link("{title}", "{url}"), &fields, format);
+            assert!(rendered.starts_with("[\\[x\\]\\(javascript:y\\)]("), "{:?}: {}", dialect, rendered);
+            assert!(rendered.ends_with("(https://example.com/wiki/A_(b\\)\\\\)"), "{:?}: {}", dialect, rendered);
+        }
+    }
+}